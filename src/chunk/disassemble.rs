@@ -0,0 +1,131 @@
+use super::{Chunk, ObjFunction, u24_to_u32};
+use crate::opcode::*;
+use std::io::{self, Write};
+
+impl Chunk {
+    pub fn disassemble(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < self.code.len() {
+            offset += self.disassemble_instruction(offset, out)?;
+        }
+        Ok(())
+    }
+
+    /// Disassembles this chunk under a `== name ==` header, followed by every function nested in it.
+    pub fn disassemble_all(&self, name: &str, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "== {} ==", name)?;
+        self.disassemble(out)?;
+        for function in self.functions() {
+            writeln!(out)?;
+            unsafe { (*function).chunk.disassemble_all(ObjFunction::name(function), out)?; }
+        }
+        Ok(())
+    }
+
+    /// Writes one instruction and returns its size in bytes.
+    /// The line column shows `|` while the source line stays the same as the previous instruction.
+    pub fn disassemble_instruction(&self, offset: usize, out: &mut impl Write) -> io::Result<usize> {
+        write!(out, "{:04} ", offset)?;
+        let line = self.get_line(offset);
+        if offset > 0 && line == self.get_line(offset - 1) {
+            write!(out, "   | ")?;
+        } else {
+            write!(out, "{:4} ", line)?;
+        }
+
+        let opcode = self.code[offset];
+        match opcode {
+            OP_RETURN => self.return_instruction(out, "OP_RETURN"),
+            OP_CONSTANT => self.constant_instruction(offset, out, "OP_CONSTANT"),
+            OP_CONSTANT_LONG => self.constant_long_instruction(offset, out, "OP_CONSTANT_LONG"),
+            OP_NEGATE => self.return_instruction(out, "OP_NEGATE"),
+            OP_ADD => self.return_instruction(out, "OP_ADD"),
+            OP_SUBTRACT => self.return_instruction(out, "OP_SUBTRACT"),
+            OP_MULTIPLY => self.return_instruction(out, "OP_MULTIPLY"),
+            OP_DIVIDE => self.return_instruction(out, "OP_DIVIDE"),
+            OP_TRUE => self.return_instruction(out, "OP_TRUE"),
+            OP_FALSE => self.return_instruction(out, "OP_FALSE"),
+            OP_NIL => self.return_instruction(out, "OP_NIL"),
+            OP_EQUAL => self.return_instruction(out, "OP_EQUAL"),
+            OP_GREATER => self.return_instruction(out, "OP_GREATER"),
+            OP_LESS => self.return_instruction(out, "OP_LESS"),
+            OP_POP => self.return_instruction(out, "OP_POP"),
+            OP_DEFINE_GLOBAL => self.return_instruction(out, "OP_DEFINE_GLOBAL"),
+            OP_DEFINE_GLOBAL_MUT => self.return_instruction(out, "OP_DEFINE_GLOBAL_MUT"),
+            OP_IMPORT => self.return_instruction(out, "OP_IMPORT"),
+            OP_EXPORT => self.return_instruction(out, "OP_EXPORT"),
+            OP_GET_PROPERTY => self.return_instruction(out, "OP_GET_PROPERTY"),
+            OP_NOT => self.return_instruction(out, "OP_NOT"),
+            OP_NOT_EQUAL => self.return_instruction(out, "OP_NOT_EQUAL"),
+            OP_GREATER_EQUAL => self.return_instruction(out, "OP_GREATER_EQUAL"),
+            OP_LESS_EQUAL => self.return_instruction(out, "OP_LESS_EQUAL"),
+            OP_GET_GLOBAL => self.return_instruction(out, "OP_GET_GLOBAL"),
+            OP_SET_GLOBAL => self.return_instruction(out, "OP_SET_GLOBAL"),
+            OP_GET_LOCAL => self.byte_instruction(offset, out, "OP_GET_LOCAL"),
+            OP_SET_LOCAL => self.byte_instruction(offset, out, "OP_SET_LOCAL"),
+            OP_MODULO => self.return_instruction(out, "OP_MODULO"),
+            OP_DUP => self.return_instruction(out, "OP_DUP"),
+            OP_CALL => self.byte_instruction(offset, out, "OP_CALL"),
+            OP_BUILD_STRING => self.byte_instruction(offset, out, "OP_BUILD_STRING"),
+            OP_PRINT => self.return_instruction(out, "OP_PRINT"),
+            OP_BUILD_LIST => self.byte_instruction(offset, out, "OP_BUILD_LIST"),
+            OP_BUILD_MAP => self.byte_instruction(offset, out, "OP_BUILD_MAP"),
+            OP_JUMP => self.jump_instruction(offset, out, "OP_JUMP"),
+            OP_JUMP_IF_FALSE => self.jump_instruction(offset, out, "OP_JUMP_IF_FALSE"),
+            OP_LOOP => self.jump_instruction(offset, out, "OP_LOOP"),
+            OP_RANGE => self.return_instruction(out, "OP_RANGE"),
+            OP_RANGE_INCLUSIVE => self.return_instruction(out, "OP_RANGE_INCLUSIVE"),
+            OP_ITER => self.return_instruction(out, "OP_ITER"),
+            OP_FOR_ITER => self.jump_instruction(offset, out, "OP_FOR_ITER"),
+            OP_TRY => self.jump_instruction(offset, out, "OP_TRY"),
+            OP_END_TRY => self.return_instruction(out, "OP_END_TRY"),
+            OP_PROPAGATE => self.return_instruction(out, "OP_PROPAGATE"),
+            OP_OK => self.return_instruction(out, "OP_OK"),
+            OP_ERR => self.return_instruction(out, "OP_ERR"),
+            OP_ASSERT => self.byte_instruction(offset, out, "OP_ASSERT"),
+            OP_ASSERT_EQ => self.return_instruction(out, "OP_ASSERT_EQ"),
+            OP_PANIC => self.byte_instruction(offset, out, "OP_PANIC"),
+            OP_NO_MATCH => self.return_instruction(out, "OP_NO_MATCH"),
+            OP_MATCH_RANGE => self.byte_instruction(offset, out, "OP_MATCH_RANGE"),
+            OP_GET_INDEX => self.return_instruction(out, "OP_GET_INDEX"),
+            OP_SET_INDEX => self.return_instruction(out, "OP_SET_INDEX"),
+            OP_DUP2 => self.return_instruction(out, "OP_DUP2"),
+            OP_INVOKE => self.byte_instruction(offset, out, "OP_INVOKE"),
+            _ => {
+                writeln!(out, "Unknown opcode {:#04x}", opcode)?;
+                Ok(1)
+            },
+        }
+    }
+
+    fn constant_instruction(&self, offset: usize, out: &mut impl Write, name: &str) -> io::Result<usize> {
+        let constant_idx = self.code[offset + 1] as usize;
+        writeln!(out, "{:<18} {:4} '{}'", name, constant_idx, self.constants[constant_idx])?;
+        Ok(2)
+    }
+
+    fn constant_long_instruction(&self, offset: usize, out: &mut impl Write, name: &str) -> io::Result<usize> {
+        let constant_idx = u24_to_u32([
+            self.code[offset + 1],
+            self.code[offset + 2],
+            self.code[offset + 3],
+        ]) as usize;
+        writeln!(out, "{:<18} {:4} '{}'", name, constant_idx, self.constants[constant_idx])?;
+        Ok(4)
+    }
+
+    fn byte_instruction(&self, offset: usize, out: &mut impl Write, name: &str) -> io::Result<usize> {
+        writeln!(out, "{:<18} {:4}", name, self.code[offset + 1])?;
+        Ok(2)
+    }
+
+    fn jump_instruction(&self, offset: usize, out: &mut impl Write, name: &str) -> io::Result<usize> {
+        writeln!(out, "{:<18} {:4} -> {}", name, offset, self.jump_target(offset).unwrap_or(0))?;
+        Ok(3)
+    }
+
+    fn return_instruction(&self, out: &mut impl Write, name: &str) -> io::Result<usize> {
+        writeln!(out, "{}", name)?;
+        Ok(1)
+    }
+}
@@ -1,4 +1,20 @@
+pub mod disassemble;
+pub mod serialize;
+pub mod verify;
+#[cfg(test)]
+mod tests;
+
 use crate::opcode::*;
+use crate::compiler::make_obj_str;
+use crate::hash;
+use crate::vm::table::Table;
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// `OP_CONSTANT_LONG` carries a 24-bit index.
+pub const MAX_CONSTANTS: usize = 1 << 24;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Values {
@@ -9,12 +25,91 @@ pub enum Values {
     Tombstone,
 }
 
-// declaration of Obj starts at [line number 143].
+impl Values {
+    /// nil and false are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Values::Nil | Values::Bool(false))
+    }
+
+    /// The one equality every part of the language uses (`==`, table keys, folding):
+    /// values of different types are never equal, strings compare by contents and
+    /// every other object by identity. Numbers follow IEEE 754, so `NaN != NaN`.
+    pub fn equals(self, other: Values) -> bool {
+        match (self, other) {
+            (Values::Number(a), Values::Number(b)) => a == b,
+            (Values::Bool(a), Values::Bool(b)) => a == b,
+            (Values::Nil, Values::Nil) | (Values::Tombstone, Values::Tombstone) => true,
+            (Values::Obj(a), Values::Obj(b)) => a == b || unsafe {
+                matches!((string_bytes(a), string_bytes(b)), (Some(a), Some(b)) if a == b)
+            },
+            _ => false,
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(self, Values::Obj(obj) if unsafe { matches!((**obj).type_obj, ObjType::String) })
+    }
+
+    /// The name runtime errors use for this value's type.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Values::Number(_) => "number",
+            Values::Bool(_) => "bool",
+            Values::Nil => "nil",
+            Values::Obj(obj) => match unsafe { (**obj).type_obj } {
+                ObjType::String => "string",
+                ObjType::Function => "function",
+                ObjType::List => "list",
+                ObjType::Map => "map",
+                ObjType::Range => "range",
+                ObjType::Iterator => "iterator",
+                ObjType::Module => "module",
+                ObjType::Result => "result",
+                ObjType::Dummy => "object",
+            },
+            Values::Tombstone => "tombstone",
+        }
+    }
+}
+
+/// This is `to_string` for every value, used by `print`, interpolation and `+` with a string:
+/// numbers in their shortest round-trip form (`3`, `0.5`, `NaN`, `inf`), strings without quotes.
+impl fmt::Display for Values {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Values::Number(num) => write!(f, "{}", num),
+            Values::Bool(b) => write!(f, "{}", b),
+            Values::Nil => write!(f, "nil"),
+            Values::Obj(obj_ptr) => unsafe {
+                match (**obj_ptr).type_obj {
+                    ObjType::String => write!(f, "{}", ObjString::as_str(*obj_ptr as *mut ObjString)),
+                    ObjType::Function if (*(*obj_ptr as *mut ObjFunction)).name.is_null() => write!(f, "<script>"),
+                    ObjType::Function => write!(f, "<fn {}>", ObjFunction::name(*obj_ptr as *mut ObjFunction)),
+                    ObjType::List => ObjList::fmt(*obj_ptr as *mut ObjList, f),
+                    ObjType::Map => ObjMap::fmt(*obj_ptr as *mut ObjMap, f),
+                    ObjType::Range => {
+                        let range = &*(*obj_ptr as *mut ObjRange);
+                        write!(f, "{}{}{}", range.start, if range.inclusive { "..=" } else { ".." }, range.end)
+                    },
+                    ObjType::Iterator => write!(f, "<iterator>"),
+                    ObjType::Module => write!(f, "<module {}>", ObjString::as_str((*(*obj_ptr as *mut ObjModule)).name)),
+                    ObjType::Result => ObjResult::fmt(*obj_ptr as *mut ObjResult, f),
+                    ObjType::Dummy => write!(f, "<obj>"),
+                }
+            },
+            Values::Tombstone => write!(f, "<tombstone>"),
+        }
+    }
+}
+
+// declaration of Obj starts at [line number 241].
 
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Values>,
-    pub lines: Vec<(usize, u32)>
+    pub lines: Vec<(usize, u32)>,
+    number_indices: HashMap<u64, usize>,
+    string_indices: HashMap<Box<[u8]>, usize>,
 }
 
 impl Chunk {
@@ -23,6 +118,8 @@ impl Chunk {
             code: Vec::with_capacity(capacity),
             constants: Vec::with_capacity(capacity * 2),
             lines: Vec::with_capacity(capacity),
+            number_indices: HashMap::new(),
+            string_indices: HashMap::new(),
         }
     }
 
@@ -36,9 +133,53 @@ impl Chunk {
     }
 
     pub fn write_constant(&mut self, constant: Values, line: u32) {
+        let index = self.add_constant(constant);
+        self.write_constant_index(index, line);
+    }
+
+    /// Numbers are matched by bit pattern and strings by contents, so a literal
+    /// repeated across the chunk shares one slot. The caller keeps ownership of an
+    /// object that turns out to be a duplicate.
+    pub fn add_constant(&mut self, constant: Values) -> usize {
+        if let Some(index) = self.find_constant(constant) {
+            return index;
+        }
+
         let index = self.constants.len();
         self.constants.push(constant);
+        match constant {
+            Values::Number(num) => { self.number_indices.insert(num.to_bits(), index); },
+            Values::Obj(obj) => {
+                if let Some(bytes) = unsafe { string_bytes(obj) } {
+                    self.string_indices.insert(bytes.into(), index);
+                }
+            },
+            _ => {},
+        }
+        index
+    }
+
+    pub fn find_constant(&self, constant: Values) -> Option<usize> {
+        match constant {
+            Values::Number(num) => self.number_indices.get(&num.to_bits()).copied(),
+            Values::Obj(obj) => unsafe { string_bytes(obj) }
+                .and_then(|bytes| self.string_indices.get(bytes).copied()),
+            _ => None,
+        }
+    }
+
+    /// Only allocates a new `ObjString` when no constant with the same contents exists.
+    pub fn add_string_constant(&mut self, start: *const u8, length: usize) -> usize {
+        let bytes = unsafe { std::slice::from_raw_parts(start, length) };
+        if let Some(&index) = self.string_indices.get(bytes) {
+            return index;
+        }
+
+        let obj_ptr = unsafe { make_obj_str(start, length) } as *mut Obj;
+        self.add_constant(Values::Obj(obj_ptr))
+    }
 
+    pub fn write_constant_index(&mut self, index: usize, line: u32) {
         if index < 256 {
             self.write_byte(OP_CONSTANT, line);
             self.write_byte(index as u8, line);
@@ -51,93 +192,32 @@ impl Chunk {
         }
     }
 
-    #[cfg(debug_assertions)]
-    pub fn chunk_peek(&self, name: &str) {
-        println!("== {} ==", name);
-        let mut i = 0;
-        while i < self.code.len() {
-            i += self.chunk_match(i);
-        }
-    }
-
-    #[cfg(debug_assertions)]
-    pub fn chunk_match(&self, idx: usize) -> usize {
-        let opcode: u8 = self.code[idx];
-        let line: u32 = self.get_line(idx);
-
-        let offset = match opcode {
-            OP_RETURN => self.return_instruction(idx, line, "OP_RETURN"),
-            OP_CONSTANT => self.constant_instruction(idx, line, "OP_CONSTANT"),
-            OP_CONSTANT_LONG => self.constant_long_instruction(idx, line, "OP_CONSTANT_LONG"),
-            OP_NEGATE => self.return_instruction(idx, line, "OP_NEGATE"),
-            OP_ADD => self.return_instruction(idx, line, "OP_ADD"),
-            OP_SUBTRACT => self.return_instruction(idx, line, "OP_SUBTRACT"),
-            OP_MULTIPLY => self.return_instruction(idx, line, "OP_MULTIPLY"),
-            OP_DIVIDE => self.return_instruction(idx, line, "OP_DIVIDE"),
-            OP_TRUE => self.return_instruction(idx, line, "OP_TRUE"),
-            OP_FALSE => self.return_instruction(idx, line, "OP_FALSE"),
-            OP_NIL => self.return_instruction(idx, line, "OP_NIL"),
-            OP_EQUAL => self.return_instruction(idx, line, "OP_EQUAL"),
-            OP_GREATER => self.return_instruction(idx, line, "OP_GREATER"),
-            OP_LESS => self.return_instruction(idx, line, "OP_LESS"),
-            OP_POP => self.return_instruction(idx, line, "OP_POP"),
-            _ => {
-                panic!("Lexer: Unknown Opcode {}", opcode);
-            },
-        };
-
-        offset
+    /// Function objects stored in this chunk's constant pool, i.e. the functions declared directly inside it.
+    pub fn functions(&self) -> impl Iterator<Item = *mut ObjFunction> + '_ {
+        self.constants.iter().filter_map(|constant| match *constant {
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Function) } => Some(obj as *mut ObjFunction),
+            _ => None,
+        })
     }
 
-    #[cfg(debug_assertions)]
-    fn constant_instruction(&self, idx: usize, line: u32, name: &str) -> usize {
-        let constant_idx = self.code[idx + 1] as usize;
-        match &self.constants[constant_idx] {
-            Values::Number(num) => {
-                println!("{:04} (line {}) {} {} {}", idx, line, name, constant_idx, num);
-            },
-            Values::Obj(obj_ptr) => {
+    /// Frees every object in the constant pool, including the pools of nested functions.
+    /// Only for chunks that never reach a VM, which otherwise owns these objects.
+    ///
+    /// # Safety
+    /// Nothing may use the constants afterwards.
+    pub unsafe fn free_constants(&self) {
+        for constant in &self.constants {
+            if let Values::Obj(obj) = *constant {
                 unsafe {
-                    match (*(*obj_ptr)).type_obj {
-                        ObjType::String => {
-                            let str_ptr: *mut ObjString = *obj_ptr as *mut ObjString;
-                            let string = std::str::from_utf8_unchecked(std::slice::from_raw_parts((*str_ptr).chars, (*str_ptr).length));
-
-                            println!("{:04} (line {}) {} {} {}", idx, line, name, constant_idx, string);
-                        },
-                        _ => panic!("you cannot add non-obj value as an obj"),
+                    if let ObjType::Function = (*obj).type_obj {
+                        (*(obj as *mut ObjFunction)).chunk.free_constants();
                     }
+                    Obj::free(obj);
                 }
-            },
-            _ => panic!("You cannot push non-literal-value"),
+            }
         }
-        2
     }
 
-    #[cfg(debug_assertions)]
-    fn constant_long_instruction(&self, idx: usize, line: u32, name: &str) -> usize {
-        let constant_idx = u24_to_u32([
-            self.code[idx + 1],
-            self.code[idx + 2],
-            self.code[idx + 3],
-        ]) as usize;
-
-        match &self.constants[constant_idx] {
-            Values::Number(num) => {
-                println!("{:04} (line {}) {} {} {}", idx, line, name, constant_idx, num);
-            },
-            _ => panic!("You cannot push non-literal-value"),
-        }
-        4
-    }
-    
-    #[cfg(debug_assertions)]
-    fn return_instruction(&self, idx: usize, line: u32, name: &str) -> usize {
-        println!("{:04} (line {}) {}", idx, line, name);
-        1
-    }
-    
-    #[cfg(debug_assertions)]
     pub fn get_line(&self, idx: usize) -> u32 {
         match self.lines.binary_search_by_key(&idx, |&(off, _)| off) {
             Ok(i) => self.lines[i].1,
@@ -145,20 +225,235 @@ impl Chunk {
             Err(i) => self.lines[i - 1].1,
         }
     }
+
+    /// Where the jump at `offset` lands. Jumps carry a 16-bit distance from the end of the
+    /// instruction, backwards for `OP_LOOP` and forwards for the rest.
+    /// `None` if the operand is missing or `OP_LOOP` would go before the start.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let operand = self.code.get(offset + 1..offset + 3)?;
+        let distance = u16::from_be_bytes([operand[0], operand[1]]) as usize;
+        if self.code[offset] == OP_LOOP {
+            (offset + 3).checked_sub(distance)
+        } else {
+            Some(offset + 3 + distance)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum ObjType {
     String,
+    Function,
+    List,
+    Map,
+    Range,
+    Iterator,
+    Module,
+    Result,
+    #[allow(dead_code)]
     Dummy,
 }
 
+/// The text lives in the same allocation, right after the header (see `ObjString::layout`).
+/// `hash` stays `None` until the string is first used as a table key.
 #[repr(C)]
 pub struct ObjString {
     pub obj: Obj,
     pub length: usize,
     pub chars: *mut u8,
-    pub hash: u32,
+    pub hash: Option<u32>,
+}
+
+/// `name` is null for the top-level script. `max_stack` is filled in by verification.
+/// `module` is the module whose globals the function reads and writes, set when the VM
+/// takes the function over; null means the globals of the script the VM was given.
+#[repr(C)]
+pub struct ObjFunction {
+    pub obj: Obj,
+    pub arity: u8,
+    pub max_stack: usize,
+    pub chunk: Chunk,
+    pub name: *mut ObjString,
+    pub module: *mut ObjModule,
+}
+
+impl ObjFunction {
+    /// # Safety
+    /// `ptr` must point to a live `ObjFunction`.
+    pub unsafe fn name<'a>(ptr: *mut ObjFunction) -> &'a str {
+        unsafe {
+            if (*ptr).name.is_null() { "<script>" } else { ObjString::as_str((*ptr).name) }
+        }
+    }
+}
+
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Values>,
+}
+
+#[repr(C)]
+pub struct ObjMap {
+    pub obj: Obj,
+    pub table: Table,
+}
+
+/// `Ok(value)` when `ok`, otherwise `Err(value)`.
+#[repr(C)]
+pub struct ObjResult {
+    pub obj: Obj,
+    pub ok: bool,
+    pub value: Values,
+}
+
+/// A file loaded by `use`, holding its top-level `let`s and `Fn`s. `path` is the resolved path
+/// the VM caches it under, which the VM allocated while importing. `exports` holds the names
+/// declared `pub`, the only globals other files may read. `loaded` turns true once the top-level
+/// code has finished, so meeting the module again before that means an import cycle.
+#[repr(C)]
+pub struct ObjModule {
+    pub obj: Obj,
+    pub name: *mut ObjString,
+    pub path: *mut ObjString,
+    pub globals: Table,
+    pub immutable_globals: Table,
+    pub exports: Table,
+    pub loaded: bool,
+}
+
+/// `start..end`, or `start..=end` when `inclusive`. Ranges are values of their own so they can be
+/// stored and passed around before a `for` loop walks them.
+#[repr(C)]
+pub struct ObjRange {
+    pub obj: Obj,
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+
+/// The hidden loop variable of a `for` loop, created by `OP_ITER` and advanced by `OP_FOR_ITER`.
+#[repr(C)]
+pub struct ObjIterator {
+    pub obj: Obj,
+    pub state: IterState,
+}
+
+/// Lists are read live, so items pushed during the loop are visited; map keys are copied
+/// when the loop starts because inserting can move every entry of the table.
+/// A range yields `start + count`; counting in an integer keeps each step exact.
+pub enum IterState {
+    List { list: *mut ObjList, index: usize },
+    Keys { keys: Vec<Values>, index: usize },
+    Chars { string: *mut ObjString, offset: usize },
+    Range { start: f64, count: u64, end: f64, inclusive: bool },
+}
+
+thread_local! {
+    /// Collections currently being formatted, so one that contains itself prints `[...]` or `{...}` instead of recursing forever.
+    static FORMATTING: RefCell<Vec<*mut Obj>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `body` unless `obj` is already being formatted further up, in which case `cycle` is written instead.
+fn format_once(obj: *mut Obj, f: &mut fmt::Formatter<'_>, cycle: &str, body: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result) -> fmt::Result {
+    if FORMATTING.with_borrow(|objects| objects.contains(&obj)) {
+        return write!(f, "{}", cycle);
+    }
+    FORMATTING.with_borrow_mut(|objects| objects.push(obj));
+    let result = body(f);
+    FORMATTING.with_borrow_mut(|objects| objects.pop());
+    result
+}
+
+/// Values inside collections are shown the way they would be written, so strings are quoted.
+fn fmt_element(value: &Values, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if value.is_string() { write!(f, "\"{}\"", value) } else { write!(f, "{}", value) }
+}
+
+impl ObjResult {
+    /// # Safety
+    /// `ptr` must point to a live `ObjResult`. A result can only hold older values, so it is never
+    /// inside itself except through a list or map, which `format_once` already guards.
+    unsafe fn fmt(ptr: *mut ObjResult, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = unsafe { &*ptr };
+        write!(f, "{}(", if result.ok { "Ok" } else { "Err" })?;
+        fmt_element(&result.value, f)?;
+        write!(f, ")")
+    }
+}
+
+impl ObjList {
+    /// # Safety
+    /// `ptr` must point to a live `ObjList`.
+    unsafe fn fmt(ptr: *mut ObjList, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format_once(ptr as *mut Obj, f, "[...]", |f| {
+            write!(f, "[")?;
+            for (i, item) in unsafe { &(*ptr).items }.iter().enumerate() {
+                if i > 0 { write!(f, ", ")?; }
+                fmt_element(item, f)?;
+            }
+            write!(f, "]")
+        })
+    }
+}
+
+impl ObjMap {
+    /// # Safety
+    /// `ptr` must point to a live `ObjMap`.
+    unsafe fn fmt(ptr: *mut ObjMap, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format_once(ptr as *mut Obj, f, "{...}", |f| {
+            write!(f, "{{")?;
+            for (i, (key, value)) in unsafe { &(*ptr).table }.iter().enumerate() {
+                if i > 0 { write!(f, ", ")?; }
+                fmt_element(&key, f)?;
+                write!(f, ": ")?;
+                fmt_element(&value, f)?;
+            }
+            write!(f, "}}")
+        })
+    }
+}
+
+/// # Safety
+/// `obj` must point to a live object.
+unsafe fn string_bytes<'a>(obj: *mut Obj) -> Option<&'a [u8]> {
+    unsafe {
+        match (*obj).type_obj {
+            ObjType::String => {
+                let s = obj as *mut ObjString;
+                Some(std::slice::from_raw_parts((*s).chars, (*s).length))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl ObjString {
+    /// Layout of a string object with `length` bytes of text, and the offset of the text in it.
+    pub fn layout(length: usize) -> (Layout, usize) {
+        Layout::new::<ObjString>().extend(Layout::array::<u8>(length).unwrap()).unwrap()
+    }
+
+    /// # Safety
+    /// `ptr` must point to a live `ObjString`.
+    pub unsafe fn hash(ptr: *mut ObjString) -> u32 {
+        unsafe {
+            match (*ptr).hash {
+                Some(hash) => hash,
+                None => {
+                    let hash = hash::fnv1a_hash((*ptr).chars, (*ptr).length);
+                    (*ptr).hash = Some(hash);
+                    hash
+                },
+            }
+        }
+    }
+
+    /// # Safety
+    /// `ptr` must point to a live `ObjString` holding valid UTF-8.
+    pub unsafe fn as_str<'a>(ptr: *mut ObjString) -> &'a str {
+        unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts((*ptr).chars, (*ptr).length)) }
+    }
 }
 
 #[repr(C)]
@@ -168,6 +463,41 @@ pub struct Obj {
 }
 
 impl Obj {
+    /// # Safety
+    /// `object` must be a live heap object that nothing else will free.
+    pub unsafe fn free(object: *mut Obj) {
+        unsafe {
+            match (*object).type_obj {
+                ObjType::String => {
+                    let s = object as *mut ObjString;
+                    let (layout, _) = ObjString::layout((*s).length);
+                    alloc::dealloc(s as *mut u8, layout);
+                },
+                // The chunk's constants are separate objects with their own owner; only the name belongs to the function.
+                ObjType::Function => {
+                    let function = Box::from_raw(object as *mut ObjFunction);
+                    if !function.name.is_null() {
+                        Obj::free(function.name as *mut Obj);
+                    }
+                },
+                // Items are references; the objects they point to are freed through their own list entry.
+                ObjType::List => drop(Box::from_raw(object as *mut ObjList)),
+                ObjType::Map => drop(Box::from_raw(object as *mut ObjMap)),
+                ObjType::Range => drop(Box::from_raw(object as *mut ObjRange)),
+                ObjType::Iterator => drop(Box::from_raw(object as *mut ObjIterator)),
+                ObjType::Result => drop(Box::from_raw(object as *mut ObjResult)),
+                // The name belongs to the module; the path and the globals' values are freed through their own entries.
+                ObjType::Module => {
+                    let module = Box::from_raw(object as *mut ObjModule);
+                    Obj::free(module.name as *mut Obj);
+                },
+                _ => panic!("memory --> leaked!!!!!!"),
+            }
+        }
+    }
+
+
+    #[allow(dead_code)]
     pub fn dummy() -> *mut Obj {
         let obj = Obj {
             type_obj: ObjType::Dummy,
@@ -177,7 +507,7 @@ impl Obj {
     }
 }
 
-fn u32_to_u24(value: u32) -> [u8; 3] {
+pub fn u32_to_u24(value: u32) -> [u8; 3] {
     [
         ((value >> 16) & 0xFF) as u8,
         ((value >> 8) & 0xFF) as u8,
@@ -185,7 +515,7 @@ fn u32_to_u24(value: u32) -> [u8; 3] {
     ]
 }
 
-fn u24_to_u32(bytes: [u8; 3]) -> u32 {
+pub fn u24_to_u32(bytes: [u8; 3]) -> u32 {
     ((bytes[0] as u32) << 16)
         | ((bytes[1] as u32) << 8)
         | (bytes[2] as u32)
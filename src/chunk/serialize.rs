@@ -0,0 +1,233 @@
+//! `.rfc` bytecode files.
+//!
+//! Layout (all integers little-endian):
+//! ```text
+//! magic      "REEF"
+//! version    u16
+//! body       the script's chunk
+//!
+//! body:
+//! constants  u32 count, then per constant a tag byte:
+//!              0x00 number    f64
+//!              0x01 string    u32 length + UTF-8 bytes
+//!              0x02 function  u32 name length + UTF-8 name, u8 arity, body
+//! code       u32 length + bytes
+//! lines      u32 count, then (u32 offset, u32 line) pairs
+//! ```
+
+use super::{Chunk, Values, Obj, ObjType, ObjString, ObjFunction};
+use super::verify::{VerifyError, MAX_FUNCTION_DEPTH};
+use crate::compiler::{make_obj_str, make_obj_function};
+use std::fmt;
+
+pub const MAGIC: &[u8; 4] = b"REEF";
+pub const VERSION: u16 = 3;
+
+const TAG_NUMBER: u8 = 0x00;
+const TAG_STRING: u8 = 0x01;
+const TAG_FUNCTION: u8 = 0x02;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownConstantTag(u8),
+    InvalidString,
+    BadLineTable,
+    TrailingBytes,
+    Invalid(VerifyError),
+}
+
+impl From<VerifyError> for BytecodeError {
+    fn from(error: VerifyError) -> Self {
+        BytecodeError::Invalid(error)
+    }
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::Truncated => write!(f, "file is truncated"),
+            BytecodeError::BadMagic => write!(f, "not a reef bytecode file"),
+            BytecodeError::UnsupportedVersion(version) => write!(f, "unsupported bytecode version {} (expected {})", version, VERSION),
+            BytecodeError::UnknownConstantTag(tag) => write!(f, "unknown constant tag {:#04x}", tag),
+            BytecodeError::InvalidString => write!(f, "string constant is not valid UTF-8"),
+            BytecodeError::BadLineTable => write!(f, "line table is out of order or out of range"),
+            BytecodeError::TrailingBytes => write!(f, "unexpected bytes after the line table"),
+            BytecodeError::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Chunk {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.code.len() * 2);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        self.write_body(&mut out);
+        out
+    }
+
+    fn write_body(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            match *constant {
+                Values::Number(num) => {
+                    out.push(TAG_NUMBER);
+                    out.extend_from_slice(&num.to_le_bytes());
+                },
+                Values::Obj(obj) => unsafe {
+                    match (*obj).type_obj {
+                        ObjType::String => {
+                            let string = ObjString::as_str(obj as *mut ObjString);
+                            out.push(TAG_STRING);
+                            out.extend_from_slice(&(string.len() as u32).to_le_bytes());
+                            out.extend_from_slice(string.as_bytes());
+                        },
+                        ObjType::Function => {
+                            let function = obj as *mut ObjFunction;
+                            let name = ObjFunction::name(function);
+                            out.push(TAG_FUNCTION);
+                            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                            out.extend_from_slice(name.as_bytes());
+                            out.push((*function).arity);
+                            (*function).chunk.write_body(out);
+                        },
+                        ObjType::List | ObjType::Map | ObjType::Range | ObjType::Iterator | ObjType::Module | ObjType::Result | ObjType::Dummy => unreachable!("runtime objects are built by the VM and dummy objects never reach the constant pool"),
+                    }
+                },
+                _ => unreachable!("only numbers and objects are stored as constants"),
+            }
+        }
+
+        out.extend_from_slice(&(self.code.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.code);
+
+        out.extend_from_slice(&(self.lines.len() as u32).to_le_bytes());
+        for &(offset, line) in &self.lines {
+            out.extend_from_slice(&(offset as u32).to_le_bytes());
+            out.extend_from_slice(&line.to_le_bytes());
+        }
+    }
+
+    /// Rejects truncated or malformed files and anything `Chunk::verify` refuses,
+    /// so a loaded chunk is as safe to run as a freshly compiled one.
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, BytecodeError> {
+        let mut chunk = Chunk::new(0);
+        match read_chunk(&mut chunk, &mut Reader { bytes, pos: 0 }) {
+            Ok(()) => Ok(chunk),
+            Err(error) => {
+                unsafe { chunk.free_constants(); }
+                Err(error)
+            },
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self.pos.checked_add(len).ok_or(BytecodeError::Truncated)?;
+        let slice = self.bytes.get(self.pos..end).ok_or(BytecodeError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, BytecodeError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+fn read_chunk(chunk: &mut Chunk, reader: &mut Reader) -> Result<(), BytecodeError> {
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    read_body(chunk, reader, 0, 0)?;
+
+    if reader.pos != reader.bytes.len() {
+        return Err(BytecodeError::TrailingBytes);
+    }
+    Ok(())
+}
+
+fn read_body(chunk: &mut Chunk, reader: &mut Reader, arity: u8, depth: usize) -> Result<(), BytecodeError> {
+    // Constants are pushed in file order (no deduplication) so the indices in the code stay valid.
+    let constant_count = reader.u32()? as usize;
+    for index in 0..constant_count {
+        let constant = match reader.u8()? {
+            TAG_NUMBER => {
+                let num = reader.f64()?;
+                chunk.number_indices.entry(num.to_bits()).or_insert(index);
+                Values::Number(num)
+            },
+            TAG_STRING => {
+                let length = reader.u32()? as usize;
+                let bytes = reader.take(length)?;
+                if std::str::from_utf8(bytes).is_err() {
+                    return Err(BytecodeError::InvalidString);
+                }
+                chunk.string_indices.entry(bytes.into()).or_insert(index);
+                Values::Obj(unsafe { make_obj_str(bytes.as_ptr(), bytes.len()) } as *mut Obj)
+            },
+            TAG_FUNCTION => {
+                let length = reader.u32()? as usize;
+                let name = reader.take(length)?;
+                if std::str::from_utf8(name).is_err() {
+                    return Err(BytecodeError::InvalidString);
+                }
+                let arity = reader.u8()?;
+                if depth == MAX_FUNCTION_DEPTH {
+                    return Err(VerifyError::TooDeeplyNested.into());
+                }
+                let mut body = Chunk::new(0);
+                if let Err(error) = read_body(&mut body, reader, arity, depth + 1) {
+                    unsafe { body.free_constants(); }
+                    return Err(error);
+                }
+                let name = unsafe { make_obj_str(name.as_ptr(), name.len()) };
+                Values::Obj(make_obj_function(name, arity, body) as *mut Obj)
+            },
+            tag => return Err(BytecodeError::UnknownConstantTag(tag)),
+        };
+        chunk.constants.push(constant);
+    }
+
+    let code_length = reader.u32()? as usize;
+    chunk.code = reader.take(code_length)?.to_vec();
+    chunk.verify(arity)?;
+
+    let line_count = reader.u32()? as usize;
+    let mut previous: Option<usize> = None;
+    for _ in 0..line_count {
+        let offset = reader.u32()? as usize;
+        let line = reader.u32()?;
+        if offset >= chunk.code.len() || previous.is_some_and(|prev| prev >= offset) {
+            return Err(BytecodeError::BadLineTable);
+        }
+        previous = Some(offset);
+        chunk.lines.push((offset, line));
+    }
+    Ok(())
+}
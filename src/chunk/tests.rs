@@ -0,0 +1,280 @@
+use super::{Chunk, Values};
+use super::serialize::{BytecodeError, MAGIC, VERSION};
+use super::verify::{VerifyError, MAX_FUNCTION_DEPTH};
+use crate::compiler::Compiler;
+use crate::lexer::Lexer;
+use crate::opcode::*;
+use crate::optimizer::OptLevel;
+use crate::vm::{VM, InterpretResult, error::RuntimeErrorKind};
+
+fn compile(source: &str) -> Chunk {
+    let mut bytes = source.as_bytes().to_vec();
+    bytes.push(b'\0');
+    Compiler::new(Lexer::new(&bytes), OptLevel::O1).compile().expect("source compiles")
+}
+
+fn disassemble(chunk: &Chunk) -> String {
+    let mut out = Vec::new();
+    chunk.disassemble(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn number(value: f64) -> Vec<u8> {
+    let mut bytes = vec![0x00];
+    bytes.extend_from_slice(&value.to_le_bytes());
+    bytes
+}
+
+fn string(text: &str) -> Vec<u8> {
+    let mut bytes = vec![0x01];
+    bytes.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(text.as_bytes());
+    bytes
+}
+
+/// A `.rfc` file whose script has these constants and this code and no line table.
+fn script(constants: &[Vec<u8>], code: &[u8]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(constants.len() as u32).to_le_bytes());
+    for constant in constants {
+        bytes.extend_from_slice(constant);
+    }
+    bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(code);
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes
+}
+
+#[test]
+fn round_trip_keeps_the_chunk() {
+    let chunk = compile("let a = 1.5;\nlet mut b = \"text\";\n{\n  let mut c = a;\n  c += 2;\n  b = b + \"!\";\n}\n");
+    let bytes = chunk.serialize();
+    let loaded = Chunk::deserialize(&bytes).expect("round-trips");
+
+    assert_eq!(loaded.code, chunk.code);
+    assert_eq!(loaded.lines, chunk.lines);
+    assert_eq!(loaded.constants.len(), chunk.constants.len());
+    assert!(loaded.constants.contains(&Values::Number(1.5)));
+    assert_eq!(loaded.serialize(), bytes);
+}
+
+#[test]
+fn damaged_files_are_rejected() {
+    let valid = script(&[number(1.0)], &[OP_CONSTANT, 0, OP_POP, OP_RETURN]);
+    assert!(Chunk::deserialize(&valid).is_ok());
+
+    let error = |bytes: &[u8]| Chunk::deserialize(bytes).err();
+    assert!(matches!(error(&valid[..valid.len() - 1]), Some(BytecodeError::Truncated)));
+    assert!(matches!(error(b"RIFF\x01\x00"), Some(BytecodeError::BadMagic)));
+
+    let mut future = valid.clone();
+    future[4] += 1;
+    assert!(matches!(error(&future), Some(BytecodeError::UnsupportedVersion(_))));
+
+    let mut trailing = valid.clone();
+    trailing.push(0);
+    assert!(matches!(error(&trailing), Some(BytecodeError::TrailingBytes)));
+
+    assert!(matches!(error(&script(&[vec![0x7F]], &[OP_RETURN])), Some(BytecodeError::UnknownConstantTag(0x7F))));
+    assert!(matches!(error(&script(&[vec![0x01, 1, 0, 0, 0, 0xFF]], &[OP_RETURN])), Some(BytecodeError::InvalidString)));
+    assert!(matches!(error(&script(&[], &[0xEE, OP_RETURN])), Some(BytecodeError::Invalid(VerifyError::UnknownOpcode { offset: 0, opcode: 0xEE }))));
+
+    let mut bad_lines = script(&[], &[OP_NIL, OP_POP, OP_RETURN]);
+    bad_lines.truncate(bad_lines.len() - 4);
+    bad_lines.extend_from_slice(&1u32.to_le_bytes());
+    bad_lines.extend_from_slice(&3u32.to_le_bytes());
+    bad_lines.extend_from_slice(&1u32.to_le_bytes());
+    assert!(matches!(error(&bad_lines), Some(BytecodeError::BadLineTable)));
+}
+
+fn invalid(code: &[u8]) -> Option<VerifyError> {
+    match Chunk::deserialize(&script(&[number(1.0)], code)).err() {
+        Some(BytecodeError::Invalid(error)) => Some(error),
+        _ => None,
+    }
+}
+
+#[test]
+fn verifier_rejects_unsafe_code() {
+    assert!(matches!(invalid(&[OP_CONSTANT, 1, OP_POP, OP_RETURN]), Some(VerifyError::BadConstantIndex { offset: 0, index: 1 })));
+    assert!(matches!(invalid(&[OP_NIL, OP_GET_LOCAL]), Some(VerifyError::MissingOperand { offset: 1 })));
+    assert!(matches!(invalid(&[OP_NIL, OP_GET_LOCAL, 2, OP_POP, OP_RETURN]), Some(VerifyError::BadLocalSlot { offset: 1, slot: 2 })));
+    assert!(matches!(invalid(&[OP_POP, OP_NIL, OP_ADD, OP_RETURN]), Some(VerifyError::StackUnderflow { offset: 2 })));
+    assert!(matches!(invalid(&[OP_NIL, OP_POP]), Some(VerifyError::FallsOffEnd)));
+    assert!(invalid(&[OP_CONSTANT, 0, OP_GET_LOCAL, 1, OP_ADD, OP_RETURN]).is_none());
+}
+
+#[test]
+fn vm_refuses_unverified_chunks() {
+    let mut chunk = Chunk::new(2);
+    chunk.write_byte(OP_POP, 1);
+    chunk.write_byte(OP_RETURN, 1);
+    assert!(matches!(VM::new(chunk).err(), Some(VerifyError::StackUnderflow { offset: 1 })));
+}
+
+#[test]
+fn compiled_code_verifies() {
+    let chunk = compile("let mut a = 1;\n{\n  let b = a + 2;\n  let c = b * a;\n  a = c;\n}\n");
+    assert!(chunk.verify(0).is_ok());
+}
+
+#[test]
+fn a_name_that_is_not_a_string_is_a_runtime_error() {
+    let chunk = Chunk::deserialize(&script(&[number(1.0)], &[OP_CONSTANT, 0, OP_GET_GLOBAL, OP_POP, OP_RETURN])).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::RuntimeError { error: RuntimeErrorKind::NotAName, .. }));
+
+    let chunk = Chunk::deserialize(&script(&[number(1.0)], &[OP_NIL, OP_CONSTANT, 0, OP_INVOKE, 0, OP_POP, OP_RETURN])).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::RuntimeError { error: RuntimeErrorKind::NotAName, .. }));
+
+    let chunk = Chunk::deserialize(&script(&[number(1.0)], &[OP_CONSTANT, 0, OP_IMPORT, OP_POP, OP_RETURN])).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::RuntimeError { error: RuntimeErrorKind::NotAName, .. }));
+
+    let chunk = Chunk::deserialize(&script(&[number(1.0)], &[OP_NIL, OP_CONSTANT, 0, OP_GET_PROPERTY, OP_POP, OP_RETURN])).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::RuntimeError { error: RuntimeErrorKind::NotAName, .. }));
+
+    let chunk = Chunk::deserialize(&script(&[number(1.0)], &[OP_CONSTANT, 0, OP_EXPORT, OP_RETURN])).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::RuntimeError { error: RuntimeErrorKind::NotAName, .. }));
+
+    let chunk = Chunk::deserialize(&script(&[string("x")], &[OP_CONSTANT, 0, OP_NIL, OP_DEFINE_GLOBAL, OP_RETURN])).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::Done));
+}
+
+#[test]
+fn listing_shows_operands_and_lines() {
+    let chunk = compile("let mut a = 1;\na = a - 1;\n");
+    assert_eq!(disassemble(&chunk), "\
+0000    1 OP_CONSTANT           0 'a'
+0002    | OP_CONSTANT           1 '1'
+0004    | OP_DEFINE_GLOBAL_MUT
+0005    2 OP_CONSTANT           0 'a'
+0007    | OP_CONSTANT           0 'a'
+0009    | OP_GET_GLOBAL
+0010    | OP_CONSTANT           1 '1'
+0012    | OP_SUBTRACT
+0013    | OP_SET_GLOBAL
+0014    | OP_POP
+0015    | OP_NIL
+0016    | OP_RETURN
+");
+}
+
+#[test]
+fn unknown_opcodes_are_listed_not_fatal() {
+    let mut chunk = Chunk::new(2);
+    chunk.write_byte(0xEE, 1);
+    chunk.write_byte(OP_RETURN, 1);
+    assert!(disassemble(&chunk).contains("Unknown opcode 0xee"));
+}
+
+/// A `.rfc` file whose script holds a function constant nested `depth` levels deep.
+/// Every body is just `nil; return`.
+fn nested_functions(depth: usize) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    for _ in 0..depth {
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&[0x02, 0, 0, 0, 0, 0]);
+    }
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    for _ in 0..=depth {
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[OP_NIL, OP_RETURN]);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn nesting_up_to_the_limit_loads() {
+    let chunk = Chunk::deserialize(&nested_functions(MAX_FUNCTION_DEPTH)).expect("loads");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::Done));
+}
+
+#[test]
+fn deeper_nesting_is_rejected() {
+    let error = Chunk::deserialize(&nested_functions(MAX_FUNCTION_DEPTH + 1)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::TooDeeplyNested))));
+    let error = Chunk::deserialize(&nested_functions(200_000)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::TooDeeplyNested))));
+}
+
+#[test]
+fn functions_round_trip() {
+    let chunk = compile("Fn add(a, b) { return a + b; }\nlet sum = add(1, 2);\n");
+    let bytes = chunk.serialize();
+    let loaded = Chunk::deserialize(&bytes).expect("round-trips");
+    assert_eq!(loaded.serialize(), bytes);
+    let mut vm = VM::new(loaded).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::Done));
+}
+
+#[test]
+fn jumps_are_verified() {
+    // Into the middle of OP_CONSTANT, past the end, and to a point reached with two depths.
+    let into_operand = [OP_JUMP, 0, 1, OP_CONSTANT, 0, OP_POP, OP_NIL, OP_RETURN];
+    let error = Chunk::deserialize(&script(&[number(1.0)], &into_operand)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::BadJump { offset: 0 }))));
+
+    let past_end = [OP_JUMP, 0, 9, OP_NIL, OP_RETURN];
+    let error = Chunk::deserialize(&script(&[], &past_end)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::BadJump { offset: 0 }))));
+
+    let uneven = [OP_TRUE, OP_JUMP_IF_FALSE, 0, 1, OP_NIL, OP_NIL, OP_RETURN];
+    let error = Chunk::deserialize(&script(&[], &uneven)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::InconsistentStack { offset: 5 }))));
+}
+
+#[test]
+fn for_iter_needs_an_iterator() {
+    let list = [OP_BUILD_LIST, 0, OP_FOR_ITER, 0, 1, OP_POP, OP_POP, OP_NIL, OP_RETURN];
+    let mut vm = VM::new(Chunk::deserialize(&script(&[], &list)).expect("loads")).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::RuntimeError { error: RuntimeErrorKind::NotAnIterator("list"), .. }));
+
+    let iterator = [OP_BUILD_LIST, 0, OP_ITER, OP_FOR_ITER, 0, 1, OP_POP, OP_POP, OP_NIL, OP_RETURN];
+    let mut vm = VM::new(Chunk::deserialize(&script(&[], &iterator)).expect("loads")).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::Done));
+}
+
+#[test]
+fn assert_needs_a_condition() {
+    let no_condition = [OP_ASSERT, 0, OP_POP, OP_NIL, OP_RETURN];
+    let error = Chunk::deserialize(&script(&[], &no_condition)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::BadOperand { offset: 0, operand: 0 }))));
+
+    let too_many = [OP_NIL, OP_NIL, OP_PANIC, 2];
+    let error = Chunk::deserialize(&script(&[], &too_many)).err();
+    assert!(matches!(error, Some(BytecodeError::Invalid(VerifyError::BadOperand { offset: 2, operand: 2 }))));
+
+    let condition = [OP_TRUE, OP_ASSERT, 1, OP_POP, OP_NIL, OP_RETURN];
+    let mut vm = VM::new(Chunk::deserialize(&script(&[], &condition)).expect("loads")).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::Done));
+}
+
+#[test]
+fn optimizing_never_overflows_a_jump() {
+    // Folding `"s0" + "t"` adds a constant ahead of the later `"s0"` loads, and pushing them past
+    // index 255 makes each one two bytes longer, which outgrows what folding saved.
+    let mut source = String::from("let mut i = 0;\nwhile i < 1 {\n");
+    for k in 0..200 {
+        source.push_str(&format!("print \"s{}\" + \"t\";\n", k));
+    }
+    for _ in 0..3 {
+        for k in 0..200 {
+            source.push_str(&format!("print \"s{}\";\n", k));
+        }
+    }
+    source.push_str(&"print i;\n".repeat(15_560));
+    source.push_str("i += 1;\n}\n");
+
+    let chunk = compile(&source);
+    assert!(chunk.code.len() > u16::MAX as usize / 2);
+    assert!(VM::new(chunk).is_ok());
+}
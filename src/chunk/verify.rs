@@ -0,0 +1,221 @@
+use super::{Chunk, ObjFunction, u24_to_u32};
+use crate::opcode::*;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    UnknownOpcode { offset: usize, opcode: u8 },
+    MissingOperand { offset: usize },
+    BadConstantIndex { offset: usize, index: usize },
+    BadLocalSlot { offset: usize, slot: u8 },
+    /// An operand outside the values its instruction takes, like `OP_ASSERT` with no condition.
+    BadOperand { offset: usize, operand: u8 },
+    StackUnderflow { offset: usize },
+    InconsistentStack { offset: usize },
+    BadJump { offset: usize },
+    FallsOffEnd,
+    TooDeeplyNested,
+}
+
+/// How deeply `Fn` bodies may nest. Loading and verifying recurse once per level,
+/// so a crafted file must not be able to nest deeper than the stack allows.
+pub const MAX_FUNCTION_DEPTH: usize = 256;
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnknownOpcode { offset, opcode } => write!(f, "unknown opcode {:#04x} at offset {}", opcode, offset),
+            VerifyError::MissingOperand { offset } => write!(f, "missing operand at offset {}", offset),
+            VerifyError::BadConstantIndex { offset, index } => write!(f, "constant index {} out of range at offset {}", index, offset),
+            VerifyError::BadLocalSlot { offset, slot } => write!(f, "local slot {} is not on the stack at offset {}", slot, offset),
+            VerifyError::BadOperand { offset, operand } => write!(f, "operand {} is out of range at offset {}", operand, offset),
+            VerifyError::StackUnderflow { offset } => write!(f, "stack underflow at offset {}", offset),
+            VerifyError::InconsistentStack { offset } => write!(f, "paths reach offset {} with different stack depths", offset),
+            VerifyError::BadJump { offset } => write!(f, "jump at offset {} does not land on an instruction", offset),
+            VerifyError::FallsOffEnd => write!(f, "execution can run past the end of the code"),
+            VerifyError::TooDeeplyNested => write!(f, "functions are nested more than {} deep", MAX_FUNCTION_DEPTH),
+        }
+    }
+}
+
+/// (values popped, values pushed, instruction size)
+fn stack_effect(opcode: u8) -> Option<(usize, usize, usize)> {
+    let effect = match opcode {
+        OP_CONSTANT => (0, 1, 2),
+        OP_CONSTANT_LONG => (0, 1, 4),
+        OP_TRUE | OP_FALSE | OP_NIL => (0, 1, 1),
+        OP_RETURN => (1, 0, 1),
+        // These pop as many values as their operand says; see `operand_pops`.
+        OP_CALL | OP_BUILD_STRING | OP_BUILD_LIST | OP_BUILD_MAP | OP_INVOKE => (0, 1, 2),
+        OP_NEGATE | OP_NOT | OP_GET_GLOBAL => (1, 1, 1),
+        OP_ADD | OP_SUBTRACT | OP_MULTIPLY | OP_DIVIDE | OP_MODULO |
+        OP_EQUAL | OP_NOT_EQUAL | OP_GREATER | OP_LESS | OP_GREATER_EQUAL | OP_LESS_EQUAL |
+        OP_SET_GLOBAL => (2, 1, 1),
+        OP_POP | OP_PRINT => (1, 0, 1),
+        OP_DUP => (1, 2, 1),
+        OP_DUP2 => (2, 4, 1),
+        OP_GET_INDEX => (2, 1, 1),
+        OP_SET_INDEX => (3, 1, 1),
+        OP_DEFINE_GLOBAL | OP_DEFINE_GLOBAL_MUT => (2, 0, 1),
+        OP_IMPORT => (1, 1, 1),
+        OP_EXPORT => (1, 0, 1),
+        OP_GET_PROPERTY => (2, 1, 1),
+        OP_GET_LOCAL => (0, 1, 2),
+        OP_JUMP | OP_LOOP => (0, 0, 3),
+        // Peeks at the condition; the code on both paths pops it.
+        OP_JUMP_IF_FALSE => (1, 1, 3),
+        OP_RANGE | OP_RANGE_INCLUSIVE => (2, 1, 1),
+        OP_ITER => (1, 1, 1),
+        // Falling through pushes the next item over the iterator; the exit jump pushes nothing.
+        OP_FOR_ITER => (1, 2, 3),
+        // Always raises a runtime error, so nothing runs after it.
+        OP_NO_MATCH => (1, 1, 1),
+        OP_MATCH_RANGE => (3, 1, 2),
+        OP_SET_LOCAL => (1, 1, 2),
+        // The jump is taken when an error is caught, with the error pushed; see `verify`.
+        OP_TRY => (0, 0, 3),
+        OP_END_TRY => (0, 0, 1),
+        // Unwraps an `Ok`; an `Err` leaves through the catch handler or a return instead.
+        OP_PROPAGATE | OP_OK | OP_ERR => (1, 1, 1),
+        // Pop their operand's worth of arguments and push nil; `OP_PANIC` never falls through.
+        OP_ASSERT | OP_PANIC => (0, 1, 2),
+        OP_ASSERT_EQ => (2, 1, 1),
+        _ => return None,
+    };
+    Some(effect)
+}
+
+/// The callee sits under its arguments, and an invoked method's receiver and name under its arguments.
+/// `OP_BUILD_MAP` pops a key and a value per pair; `OP_BUILD_STRING`, `OP_BUILD_LIST`, `OP_ASSERT`
+/// and `OP_PANIC` pop exactly their operand.
+fn operand_pops(opcode: u8, operand: u8) -> usize {
+    match opcode {
+        OP_CALL => operand as usize + 1,
+        OP_INVOKE => operand as usize + 2,
+        OP_BUILD_MAP => operand as usize * 2,
+        _ => operand as usize,
+    }
+}
+
+/// What an instruction does to the stack on the path that falls through to the next one:
+/// (values popped, values pushed). The compiler uses this to keep its running stack depth.
+pub fn fallthrough_effect(opcode: u8, operand: u8) -> (usize, usize) {
+    let (mut pops, pushes, _) = stack_effect(opcode).expect("only known opcodes have a stack effect");
+    if let OP_CALL | OP_BUILD_STRING | OP_BUILD_LIST | OP_BUILD_MAP | OP_INVOKE | OP_ASSERT | OP_PANIC = opcode {
+        pops = operand_pops(opcode, operand);
+    }
+    (pops, pushes)
+}
+
+impl Chunk {
+    /// Checks that every instruction is well formed and that no path through the chunk
+    /// underflows the stack, reads a missing local or runs past the end of the code.
+    /// Value types are not tracked: an instruction that needs a particular kind of value
+    /// checks it when it runs.
+    /// `arity` arguments sit above the callee in slot 0 when the chunk starts.
+    /// Returns the maximum stack depth, which the VM preallocates.
+    pub fn verify(&self, arity: u8) -> Result<usize, VerifyError> {
+        let code = &self.code;
+        let mut is_start = vec![false; code.len()];
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let opcode = code[offset];
+            let (_, _, size) = stack_effect(opcode).ok_or(VerifyError::UnknownOpcode { offset, opcode })?;
+            let operands = code.get(offset + 1..offset + size).ok_or(VerifyError::MissingOperand { offset })?;
+
+            let constant_index = match (opcode, operands) {
+                (OP_CONSTANT, [index]) => Some(*index as usize),
+                (OP_CONSTANT_LONG, [b0, b1, b2]) => Some(u24_to_u32([*b0, *b1, *b2]) as usize),
+                _ => None,
+            };
+            if let Some(index) = constant_index && index >= self.constants.len() {
+                return Err(VerifyError::BadConstantIndex { offset, index });
+            }
+            // `OP_ASSERT` takes a condition and maybe a message, `OP_PANIC` maybe a message.
+            let operand_ok = match (opcode, operands) {
+                (OP_ASSERT, [count]) => matches!(count, 1 | 2),
+                (OP_PANIC, [count]) => matches!(count, 0 | 1),
+                _ => true,
+            };
+            if !operand_ok {
+                return Err(VerifyError::BadOperand { offset, operand: operands[0] });
+            }
+
+            is_start[offset] = true;
+            offset += size;
+        }
+
+        let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+        let base = arity as usize + 1;
+        let mut worklist: Vec<(usize, usize)> = vec![(0, base)];
+        let mut max_depth = base;
+
+        while let Some((offset, depth)) = worklist.pop() {
+            if offset >= code.len() || !is_start[offset] {
+                return Err(VerifyError::FallsOffEnd);
+            }
+            match depths[offset] {
+                Some(seen) if seen == depth => continue,
+                Some(_) => return Err(VerifyError::InconsistentStack { offset }),
+                None => depths[offset] = Some(depth),
+            }
+
+            let opcode = code[offset];
+            let (_, _, size) = stack_effect(opcode).unwrap();
+            let (pops, pushes) = fallthrough_effect(opcode, code.get(offset + 1).copied().unwrap_or(0));
+            if depth < pops {
+                return Err(VerifyError::StackUnderflow { offset });
+            }
+            if let OP_GET_LOCAL | OP_SET_LOCAL = opcode {
+                let slot = code[offset + 1];
+                if slot as usize >= depth {
+                    return Err(VerifyError::BadLocalSlot { offset, slot });
+                }
+            }
+
+            let next_depth = depth - pops + pushes;
+            max_depth = max_depth.max(next_depth);
+
+            if let OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP | OP_FOR_ITER | OP_TRY = opcode {
+                let target = self.jump_target(offset)
+                    .filter(|&target| target < code.len() && is_start[target])
+                    .ok_or(VerifyError::BadJump { offset })?;
+                let target_depth = match opcode {
+                    OP_FOR_ITER => depth,
+                    OP_TRY => depth + 1,
+                    _ => next_depth,
+                };
+                max_depth = max_depth.max(target_depth);
+                worklist.push((target, target_depth));
+            }
+            if !matches!(opcode, OP_RETURN | OP_JUMP | OP_LOOP | OP_NO_MATCH | OP_PANIC) {
+                worklist.push((offset + size, next_depth));
+            }
+        }
+
+        Ok(max_depth)
+    }
+}
+
+/// Verifies a function and every function nested in its constant pool,
+/// recording each one's maximum stack depth.
+///
+/// # Safety
+/// `function` and the functions nested in it must be live and not shared with another thread.
+pub unsafe fn verify_function(function: *mut ObjFunction) -> Result<(), VerifyError> {
+    unsafe { verify_nested(function, 0) }
+}
+
+unsafe fn verify_nested(function: *mut ObjFunction, depth: usize) -> Result<(), VerifyError> {
+    if depth > MAX_FUNCTION_DEPTH {
+        return Err(VerifyError::TooDeeplyNested);
+    }
+    unsafe {
+        (*function).max_stack = (*function).chunk.verify((*function).arity)?;
+        for nested in (*function).chunk.functions() {
+            verify_nested(nested, depth + 1)?;
+        }
+    }
+    Ok(())
+}
@@ -2,12 +2,18 @@ pub mod rules;
 pub mod precedence;
 
 use crate::lexer::{Lexer, token::{Token, tokentype::TokenType}};
-use crate::chunk::{Chunk, Values, Obj, ObjType, ObjString};
-use crate::hash;
+use crate::chunk::{Chunk, Values, Obj, ObjType, ObjString, ObjFunction, ObjList, ObjMap, ObjRange, ObjIterator, ObjModule, ObjResult, IterState, MAX_CONSTANTS, verify};
+use crate::vm::table::Table;
+use crate::optimizer::{self, OptLevel};
 use rules::get_rule;
 use precedence::Precedence;
 use crate::opcode::*;
-use std::alloc::{self, Layout};
+use std::alloc;
+use std::mem;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 
 pub struct Compiler {
     token_stream: Lexer,
@@ -16,10 +22,67 @@ pub struct Compiler {
     previous: Token,
     is_error: bool,
     panic_mode: bool,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    opt_level: OptLevel,
+    enclosing: Vec<Enclosing>,
+    /// Whether the expression `parse_precedence` last compiled is known to be a string.
+    string_result: bool,
+    loops: Vec<Loop>,
+    /// Values on the stack above the frame base when the code emitted so far falls through,
+    /// counting expression temporaries as well as locals.
+    stack_depth: usize,
+    /// `try` blocks around the code being compiled in the current function.
+    try_depth: usize,
+    /// Directory of the file being compiled, which `use` paths are relative to.
+    base_dir: PathBuf,
+    /// The items of the modules this file `use`s, by the global each one is bound to.
+    modules: HashMap<String, ModuleItems>,
+    /// The module global just compiled, if a `.` follows it; `dot` checks the item against it.
+    module_receiver: Option<String>,
+}
+
+/// The top-level `let`s and `Fn`s of a module file, and which of them are `pub`.
+struct ModuleItems {
+    defined: HashSet<String>,
+    exported: HashSet<String>,
+}
+
+/// State of the function that was being compiled when a nested `Fn` started.
+/// It is swapped back into the compiler once the nested body is done.
+struct Enclosing {
+    chunk: Chunk,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    stack_depth: usize,
+    try_depth: usize,
+}
+
+/// The innermost loops being compiled in the current function.
+/// `break` and `continue` pop the stack down to `stack_depth` and leave the `try` blocks
+/// entered since `try_depth` before jumping; `breaks` are the jumps to patch once the loop's exit is known.
+struct Loop {
+    start: usize,
+    stack_depth: usize,
+    try_depth: usize,
+    breaks: Vec<usize>,
+}
+
+/// `depth` stays `None` until the initializer has been compiled,
+/// so `let a = a;` can be rejected inside a block.
+/// `slot` is the stack position the value lives in, which is not the index in `locals`
+/// when the local is declared in the middle of an expression, above its temporaries.
+/// Only `let mut` locals and `mut` parameters can be assigned.
+pub struct Local {
+    name: Token,
+    depth: Option<usize>,
+    slot: u8,
+    mutable: bool,
 }
 
 impl Compiler {
-    pub fn new(token_stream: Lexer) -> Self {
+    pub fn new(token_stream: Lexer, opt_level: OptLevel) -> Self {
         Self {
             token_stream,
             chunk: Chunk::new(2048),
@@ -27,6 +90,17 @@ impl Compiler {
             previous: Token::dummy(),
             is_error: false,
             panic_mode: false,
+            locals: function_locals(),
+            scope_depth: 0,
+            opt_level,
+            enclosing: Vec::new(),
+            string_result: false,
+            loops: Vec::new(),
+            stack_depth: 1,
+            try_depth: 0,
+            base_dir: PathBuf::new(),
+            modules: HashMap::new(),
+            module_receiver: None,
         }
     }
 
@@ -40,13 +114,13 @@ impl Compiler {
 
     pub fn output_chunk(mut self) -> Option<Chunk> {
         if self.is_error {
+            unsafe { self.chunk.free_constants(); }
             return None;
         }
-        self.chunk.write_byte(OP_RETURN, self.previous.line as u32);
-        
-        #[cfg(debug_assertions)]
-        {
-            self.chunk.chunk_peek("test at Compiler");
+        self.emit_return(self.previous.line as u32);
+
+        if self.opt_level == OptLevel::O1 {
+            self.chunk = optimizer::optimize(self.chunk);
         }
 
         Some(self.chunk)
@@ -54,10 +128,20 @@ impl Compiler {
 
     pub fn consume(&mut self, expected: TokenType) {
         if self.current.token_type == expected { return self.advance(); }
-        self.error_at_current();
+        if !self.panic_mode { self.error_at_current(); }
         self.panic_mode = true;
     }
 
+    pub fn check(&self, expected: TokenType) -> bool {
+        self.current.token_type == expected
+    }
+
+    pub fn match_token(&mut self, expected: TokenType) -> bool {
+        if !self.check(expected) { return false; }
+        self.advance();
+        true
+    }
+
     pub fn advance(&mut self) {
         self.previous = self.current;
 
@@ -81,21 +165,36 @@ impl Compiler {
         self.is_error = true;
     }
 
+    pub fn error(&mut self, message: &str) {
+        if self.panic_mode { return; }
+        self.panic_mode = true;
+
+        let token = self.previous;
+        let lexeme = unsafe {
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(token.start, token.length))
+        };
+        eprintln!("[line {}] Error! : '{}' at \"{}\"...", token.line, message, lexeme);
+
+        self.is_error = true;
+    }
+
     pub fn declaration(&mut self) {
         match self.current.token_type {
             TokenType::Let => {
-                self.consume(TokenType::Identifier);
-                let key = self.previous;
-                let obj_ptr: *mut Obj = unsafe { make_obj_str(key.start, key.line) as *mut Obj };
-                self.chunk.write_constant(Values::Obj(obj_ptr), key.line as u32);
-
-                if self.current.token_type == TokenType::Equal {
-                    self.parse_precedence(Precedence::Assignment);
-                } else {
-                    self.chunk.write_byte(OP_NIL, key.line as u32);
-                }
-                self.consume(TokenType::Semicolon);
-                self.chunk.write_byte(OP_DEFINE_GLOBAL, key.line as u32);
+                self.advance();
+                self.let_declaration();
+            },
+            TokenType::Fn => {
+                self.advance();
+                self.fn_declaration();
+            },
+            TokenType::Use => {
+                self.advance();
+                self.use_declaration();
+            },
+            TokenType::Pub => {
+                self.advance();
+                self.pub_declaration();
             },
             _ => self.statement(),
         }
@@ -103,40 +202,664 @@ impl Compiler {
         if self.panic_mode { self.synchronize(); }
     }
 
+    /// Globals keep their name on the stack under the value (`OP_DEFINE_GLOBAL` pops both),
+    /// locals simply leave the value in their stack slot.
+    /// Assigning to a local declared without `mut` is a compile error; for globals, which may be
+    /// assigned from functions compiled before their `let`, the VM checks at runtime.
+    /// Returns the name, for `pub` to export.
+    pub fn let_declaration(&mut self) -> Token {
+        let mutable = self.match_token(TokenType::Mut);
+        self.consume(TokenType::Identifier);
+        let name = self.previous;
+        let line = name.line as u32;
+
+        if self.scope_depth > 0 {
+            self.declare_local(name, mutable);
+        } else {
+            self.modules.remove(self.lexeme(name));
+            self.identifier_constant(name);
+        }
+
+        if self.match_token(TokenType::Equal) {
+            self.parse_precedence(Precedence::Assignment);
+        } else {
+            self.emit(OP_NIL, line);
+        }
+        self.consume(TokenType::Semicolon);
+
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit(if mutable { OP_DEFINE_GLOBAL_MUT } else { OP_DEFINE_GLOBAL }, line);
+        }
+        name
+    }
+
+    /// `pub let` and `pub Fn` define the global as usual and then export it, which is what lets
+    /// other files read it as `module.name`. Everything else a module defines stays private to it.
+    pub fn pub_declaration(&mut self) {
+        let line = self.previous.line as u32;
+        if self.scope_depth > 0 {
+            self.error("Only top-level items can be 'pub'");
+        }
+        let name = match self.current.token_type {
+            TokenType::Let => {
+                self.advance();
+                self.let_declaration()
+            },
+            TokenType::Fn => {
+                self.advance();
+                self.fn_declaration()
+            },
+            _ => {
+                self.error("Expected 'let' or 'Fn' after 'pub'");
+                return;
+            },
+        };
+        self.identifier_constant(name);
+        self.emit(OP_EXPORT, line);
+    }
+
+    /// `use "dir/file.rf";` or `use dir::file;` binds the module to an immutable global named after the file.
+    /// The path stays relative to the importing file, so bytecode can move along with its sources;
+    /// it is only checked here. Resolving, loading, running and caching the module happen in `OP_IMPORT`.
+    pub fn use_declaration(&mut self) {
+        let line = self.previous.line as u32;
+        if self.scope_depth > 0 {
+            self.error("Can only use modules at the top level of a file");
+        }
+
+        let path = if self.match_token(TokenType::String) {
+            // The lexeme still has its quotes; escapes and interpolation have no place in a path.
+            let lexeme = self.lexeme(self.previous);
+            lexeme[1..lexeme.len() - 1].to_string()
+        } else {
+            self.consume(TokenType::Identifier);
+            let mut path = self.lexeme(self.previous).to_string();
+            while self.match_token(TokenType::ColonColon) {
+                self.consume(TokenType::Identifier);
+                path.push('/');
+                path.push_str(self.lexeme(self.previous));
+            }
+            path + ".rf"
+        };
+        let relative = Path::new(&path);
+
+        let name = relative.file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_string();
+        if !is_identifier(&name) {
+            self.error(&format!("Module file name '{}' is not a valid identifier", name));
+            return;
+        }
+        let Ok(items) = module_items(&self.base_dir.join(relative)) else {
+            self.error(&format!("Can't find module '{}'", path));
+            return;
+        };
+        self.modules.insert(name.clone(), items);
+
+        self.consume(TokenType::Semicolon);
+
+        let index = self.chunk.add_string_constant(name.as_ptr(), name.len());
+        self.emit_constant_index(index, line);
+        let index = self.chunk.add_string_constant(path.as_ptr(), path.len());
+        self.emit_constant_index(index, line);
+        self.emit(OP_IMPORT, line);
+        self.emit(OP_DEFINE_GLOBAL, line);
+    }
+
+    fn lexeme<'a>(&self, token: Token) -> &'a str {
+        unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(token.start, token.length)) }
+    }
+
+    /// A local function can call itself by name, but can't see the other locals around it.
+    /// Returns the name, for `pub` to export.
+    pub fn fn_declaration(&mut self) -> Token {
+        self.consume(TokenType::Identifier);
+        let name = self.previous;
+        let line = name.line as u32;
+
+        if self.scope_depth > 0 {
+            self.declare_local(name, false);
+            self.mark_initialized();
+        } else {
+            self.modules.remove(self.lexeme(name));
+            self.identifier_constant(name);
+        }
+
+        self.function(name);
+
+        if self.scope_depth == 0 {
+            self.emit(OP_DEFINE_GLOBAL, line);
+        }
+        name
+    }
+
+    /// Compiles parameters and body into a fresh chunk and loads the finished function as a constant.
+    /// Slot 0 of every frame holds the function itself, so parameters start at slot 1.
+    /// A local function names that slot after itself so it can call itself.
+    pub fn function(&mut self, name: Token) {
+        let is_local = self.scope_depth > 0;
+        if self.enclosing.len() == verify::MAX_FUNCTION_DEPTH {
+            self.error("Too many nested functions");
+        }
+        self.enclosing.push(Enclosing {
+            chunk: mem::replace(&mut self.chunk, Chunk::new(256)),
+            locals: mem::replace(&mut self.locals, function_locals()),
+            scope_depth: mem::replace(&mut self.scope_depth, 0),
+            loops: mem::take(&mut self.loops),
+            stack_depth: mem::replace(&mut self.stack_depth, 1),
+            try_depth: mem::replace(&mut self.try_depth, 0),
+        });
+        if is_local {
+            self.locals[0].name = name;
+        }
+        self.begin_scope();
+
+        let mut arity: usize = 0;
+        self.consume(TokenType::LeftParen);
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arity += 1;
+                if arity > u8::MAX as usize {
+                    self.error("Can't have more than 255 parameters");
+                }
+                let mutable = self.match_token(TokenType::Mut);
+                self.consume(TokenType::Identifier);
+                self.declare_local(self.previous, mutable);
+                self.mark_initialized();
+                self.stack_depth += 1;
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightParen);
+        // The body is a block expression, so a last expression without `;` is returned.
+        self.consume(TokenType::LeftBrace);
+        let base_depth = self.stack_depth;
+        self.begin_scope();
+        self.block_expression_items(base_depth, false);
+        self.emit(OP_RETURN, self.previous.line as u32);
+
+        let enclosing = self.enclosing.pop().expect("function() always pushes its enclosing state");
+        let mut chunk = mem::replace(&mut self.chunk, enclosing.chunk);
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.loops = enclosing.loops;
+        self.stack_depth = enclosing.stack_depth;
+        self.try_depth = enclosing.try_depth;
+
+        if self.opt_level == OptLevel::O1 && !self.is_error {
+            chunk = optimizer::optimize(chunk);
+        }
+        let name = unsafe { make_obj_str(name.start, name.length) };
+        let function = make_obj_function(name, arity.min(u8::MAX as usize) as u8, chunk);
+        let index = self.chunk.add_constant(Values::Obj(function as *mut Obj));
+        self.emit_constant_index(index, self.previous.line as u32);
+    }
+
     pub fn statement(&mut self) {
         match self.current.token_type {
+            TokenType::LeftBrace => {
+                self.advance();
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+            },
+            TokenType::Return => {
+                self.advance();
+                self.return_statement();
+            },
+            TokenType::Print => {
+                self.advance();
+                self.print_statement();
+            },
+            TokenType::If => {
+                self.advance();
+                self.if_statement();
+            },
+            TokenType::While => {
+                self.advance();
+                self.while_statement();
+            },
+            TokenType::For => {
+                self.advance();
+                if self.match_token(TokenType::LeftParen) {
+                    self.c_for_statement();
+                } else {
+                    self.for_statement();
+                }
+            },
+            TokenType::Break | TokenType::Continue => {
+                self.advance();
+                self.loop_jump_statement();
+            },
             _ => self.expression_statement(),
         }
     }
 
-    pub fn expression_statement(&mut self) {
+    pub fn print_statement(&mut self) {
+        let line = self.previous.line as u32;
+        self.parse_precedence(Precedence::Assignment);
+        self.consume(TokenType::Semicolon);
+        self.emit(OP_PRINT, line);
+    }
+
+    /// The condition stays on the stack for `OP_JUMP_IF_FALSE`, so each branch pops it first.
+    /// `else if` chains by compiling the nested `if` as the else branch.
+    pub fn if_statement(&mut self) {
+        let line = self.previous.line as u32;
         self.parse_precedence(Precedence::Assignment);
+
+        let else_jump = self.emit_jump(OP_JUMP_IF_FALSE, line);
+        let else_depth = self.stack_depth;
+        self.emit(OP_POP, line);
+        self.consume(TokenType::LeftBrace);
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        let end_jump = self.emit_jump(OP_JUMP, line);
+
+        self.patch_jump(else_jump);
+        self.stack_depth = else_depth;
+        self.emit(OP_POP, line);
+        if self.match_token(TokenType::Else) {
+            if self.match_token(TokenType::If) {
+                self.if_statement();
+            } else {
+                self.consume(TokenType::LeftBrace);
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+            }
+        }
+        self.patch_jump(end_jump);
+    }
+
+    /// The condition stays on the stack for `OP_JUMP_IF_FALSE`, so both the body and the exit pop it.
+    pub fn while_statement(&mut self) {
+        let line = self.previous.line as u32;
+        let loop_start = self.chunk.code.len();
+        self.parse_precedence(Precedence::Assignment);
+
+        let exit_jump = self.emit_jump(OP_JUMP_IF_FALSE, line);
+        let exit_depth = self.stack_depth;
+        self.emit(OP_POP, line);
+        self.loops.push(Loop { start: loop_start, stack_depth: self.stack_depth, try_depth: self.try_depth, breaks: Vec::new() });
+        self.consume(TokenType::LeftBrace);
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_loop(loop_start, line);
+        let finished = self.loops.pop().expect("while_statement pushed its loop");
+
+        self.patch_jump(exit_jump);
+        self.stack_depth = exit_depth;
+        self.emit(OP_POP, line);
+        for jump in finished.breaks { self.patch_jump(jump); }
+    }
+
+    /// `for x in iterable { ... }`. The iterator made by `OP_ITER` lives in a hidden local
+    /// under the loop variable; `OP_FOR_ITER` pushes the next item as `x` or jumps past the loop.
+    pub fn for_statement(&mut self) {
+        let line = self.previous.line as u32;
+        self.consume(TokenType::Identifier);
+        let name = self.previous;
+        self.consume(TokenType::In);
+
+        // Both locals are declared before their value is pushed into their slot.
+        self.begin_scope();
+        self.declare_local(Token::dummy(), false);
+        self.mark_initialized();
+        self.parse_precedence(Precedence::Assignment);
+        self.emit(OP_ITER, line);
+
+        let loop_start = self.chunk.code.len();
+        self.loops.push(Loop { start: loop_start, stack_depth: self.stack_depth, try_depth: self.try_depth, breaks: Vec::new() });
+        self.begin_scope();
+        self.declare_local(name, false);
+        self.mark_initialized();
+        let exit_jump = self.emit_jump(OP_FOR_ITER, line);
+        self.consume(TokenType::LeftBrace);
+        self.block();
+        self.end_scope();
+        self.emit_loop(loop_start, line);
+        let finished = self.loops.pop().expect("for_statement pushed its loop");
+
+        self.patch_jump(exit_jump);
+        for jump in finished.breaks { self.patch_jump(jump); }
+        self.end_scope();
+    }
+
+    /// `for (let i = 0; i < 3; i += 1) { ... }`, where every clause may be left out.
+    /// A `let` in the initializer is scoped to the loop. The step is compiled ahead of the body,
+    /// so entering the body jumps over it, and the end of the body and `continue` loop back to it.
+    pub fn c_for_statement(&mut self) {
+        let line = self.previous.line as u32;
+        self.begin_scope();
+        if self.match_token(TokenType::Let) {
+            self.let_declaration();
+        } else if !self.match_token(TokenType::Semicolon) {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk.code.len();
+        let mut exit = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.parse_precedence(Precedence::Assignment);
+            self.consume(TokenType::Semicolon);
+            exit = Some((self.emit_jump(OP_JUMP_IF_FALSE, line), self.stack_depth));
+            self.emit(OP_POP, line);
+        }
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OP_JUMP, line);
+            let step_start = self.chunk.code.len();
+            self.parse_precedence(Precedence::Assignment);
+            self.emit(OP_POP, line);
+            self.consume(TokenType::RightParen);
+            self.emit_loop(loop_start, line);
+            loop_start = step_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loops.push(Loop { start: loop_start, stack_depth: self.stack_depth, try_depth: self.try_depth, breaks: Vec::new() });
+        self.consume(TokenType::LeftBrace);
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_loop(loop_start, line);
+        let finished = self.loops.pop().expect("c_for_statement pushed its loop");
+
+        if let Some((exit_jump, exit_depth)) = exit {
+            self.patch_jump(exit_jump);
+            self.stack_depth = exit_depth;
+            self.emit(OP_POP, line);
+        }
+        for jump in finished.breaks { self.patch_jump(jump); }
+        self.end_scope();
+    }
+
+    /// `break` and `continue` pop everything pushed since the loop body began (for `for`, also the
+    /// loop variable) but leave the locals of the scopes around the loop alone.
+    /// The code after them is unreachable, so it is compiled as if they had popped nothing.
+    pub fn loop_jump_statement(&mut self) {
+        let keyword = self.previous;
+        let line = keyword.line as u32;
+
+        let Some(innermost) = self.loops.last() else {
+            if keyword.token_type == TokenType::Break {
+                self.error("Can't use 'break' outside of a loop");
+            } else {
+                self.error("Can't use 'continue' outside of a loop");
+            }
+            return;
+        };
+        let (start, loop_depth, loop_try_depth) = (innermost.start, innermost.stack_depth, innermost.try_depth);
         self.consume(TokenType::Semicolon);
-        self.chunk.write_byte(OP_POP, self.current.line as u32);
+
+        for _ in loop_try_depth..self.try_depth {
+            self.emit(OP_END_TRY, line);
+        }
+        let stack_depth = self.stack_depth;
+        for _ in loop_depth..stack_depth {
+            self.emit(OP_POP, line);
+        }
+        if keyword.token_type == TokenType::Break {
+            let jump = self.emit_jump(OP_JUMP, line);
+            self.loops.last_mut().expect("checked above").breaks.push(jump);
+        } else {
+            self.emit_loop(start, line);
+        }
+        self.stack_depth = stack_depth;
+    }
+
+    fn emit(&mut self, opcode: u8, line: u32) {
+        self.chunk.write_byte(opcode, line);
+        self.track_depth(opcode, 0);
+    }
+
+    fn emit_with(&mut self, opcode: u8, operand: u8, line: u32) {
+        self.chunk.write_byte(opcode, line);
+        self.chunk.write_byte(operand, line);
+        self.track_depth(opcode, operand);
+    }
+
+    /// Saturates so code emitted after a compile error can't underflow the count.
+    fn track_depth(&mut self, opcode: u8, operand: u8) {
+        let (pops, pushes) = verify::fallthrough_effect(opcode, operand);
+        self.stack_depth = self.stack_depth.saturating_sub(pops) + pushes;
+    }
+
+    /// Writes `opcode` with a placeholder distance and returns the offset to hand to `patch_jump`.
+    fn emit_jump(&mut self, opcode: u8, line: u32) -> usize {
+        self.emit_with(opcode, 0xff, line);
+        self.chunk.write_byte(0xff, line);
+        self.chunk.code.len() - 3
+    }
+
+    /// Points the jump at `offset` to the next instruction to be written.
+    fn patch_jump(&mut self, offset: usize) {
+        let Ok(distance) = u16::try_from(self.chunk.code.len() - offset - 3) else {
+            self.error("Too much code to jump over");
+            return;
+        };
+        self.chunk.code[offset + 1..offset + 3].copy_from_slice(&distance.to_be_bytes());
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, line: u32) {
+        self.emit(OP_LOOP, line);
+        let Ok(distance) = u16::try_from(self.chunk.code.len() + 2 - loop_start) else {
+            self.error("Loop body too large");
+            return;
+        };
+        self.chunk.write_byte((distance >> 8) as u8, line);
+        self.chunk.write_byte(distance as u8, line);
+    }
+
+    pub fn return_statement(&mut self) {
+        let line = self.previous.line as u32;
+        if self.enclosing.is_empty() {
+            self.error("Can't return from top-level code");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return(line);
+        } else {
+            self.parse_precedence(Precedence::Assignment);
+            self.consume(TokenType::Semicolon);
+            self.emit(OP_RETURN, line);
+        }
+    }
+
+    /// Functions without an explicit `return` (and the script itself) return nil.
+    fn emit_return(&mut self, line: u32) {
+        self.emit(OP_NIL, line);
+        self.emit(OP_RETURN, line);
+    }
+
+    pub fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace);
+    }
+
+    pub fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    pub fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+
+        while let Some(local) = self.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= self.scope_depth) { break; }
+            self.locals.pop();
+            self.emit(OP_POP, self.previous.line as u32);
+        }
+    }
+
+    /// A `match` or `try` ending the statement may leave out the `;`, like a block.
+    pub fn expression_statement(&mut self) {
+        let starts_block_like = matches!(self.current.token_type, TokenType::Match | TokenType::Try);
+        self.parse_precedence(Precedence::Assignment);
+        if !(starts_block_like && self.previous.token_type == TokenType::RightBrace) || self.check(TokenType::Semicolon) {
+            self.consume(TokenType::Semicolon);
+        }
+        self.emit(OP_POP, self.previous.line as u32);
     }
 
     pub fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
+        let can_assign = precedence <= Precedence::Assignment;
 
+        // Whether the value so far is known to be a string, which makes every `+` after it a concatenation.
+        let mut is_string = matches!(self.previous.token_type, TokenType::String | TokenType::Interpolation);
         if let Some(prefix_fn) = get_rule(self.previous.token_type).prefix {
-            prefix_fn(self);
+            prefix_fn(self, can_assign);
         } else {
             self.error_at_current();
             return;
         }
 
         while precedence <= get_rule(self.current.token_type).precedence {
+            if is_string && self.check(TokenType::Plus) {
+                self.concatenation();
+                continue;
+            }
             self.advance();
+            let operator = self.previous.token_type;
 
-            if let Some(infix_fn) = get_rule(self.previous.token_type).infix {
-                infix_fn(self);
+            if let Some(infix_fn) = get_rule(operator).infix {
+                infix_fn(self, can_assign);
             } else {
                 self.error_at_current();
                 break;
             }
+            // `x + "a"` is a string whether or not `x` is.
+            is_string = operator == TokenType::Plus && self.string_result;
+        }
+        self.string_result = is_string;
+
+        if can_assign && (self.check(TokenType::Equal) || compound_operator(self.current.token_type).is_some()) {
+            self.advance();
+            self.error("Invalid assignment target");
+        }
+    }
+
+    pub fn identifier_constant(&mut self, name: Token) {
+        let index = self.chunk.add_string_constant(name.start, name.length);
+        self.emit_constant_index(index, name.line as u32);
+    }
+
+    pub fn emit_constant_index(&mut self, index: usize, line: u32) {
+        if index >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk");
+            return;
+        }
+        self.chunk.write_constant_index(index, line);
+        self.stack_depth += 1;
+    }
+
+    /// The local's value is the next one pushed, so it takes the slot at the current stack depth.
+    pub fn declare_local(&mut self, name: Token, mutable: bool) {
+        self.declare_local_at(name, self.stack_depth, mutable);
+    }
+
+    /// Declares a local naming a value already on the stack, such as a `match` binding.
+    fn declare_local_at(&mut self, name: Token, slot: usize, mutable: bool) {
+        let duplicated = self.locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= self.scope_depth))
+            .any(|local| identifiers_equal(&local.name, &name));
+        if duplicated {
+            self.error("Already a variable with this name in this scope");
+        }
+
+        if self.locals.len() == UINT8_COUNT || slot > u8::MAX as usize {
+            self.error("Too many local variables in function");
+            return;
+        }
+        self.locals.push(Local { name, depth: None, slot: slot as u8, mutable });
+    }
+
+    pub fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+        }
+    }
+
+    /// The local's slot and whether it may be assigned.
+    pub fn resolve_local(&mut self, name: &Token) -> Option<(u8, bool)> {
+        let index = self.locals.iter().rposition(|local| identifiers_equal(&local.name, name))?;
+        if self.locals[index].depth.is_none() {
+            self.error("Can't read local variable in its own initializer");
+        }
+        Some((self.locals[index].slot, self.locals[index].mutable))
+    }
+
+    /// Without this check the name would silently fall through to a global of the same name.
+    fn is_enclosing_local(&self, name: &Token) -> bool {
+        self.enclosing.iter()
+            .any(|enclosing| enclosing.locals.iter().any(|local| identifiers_equal(&local.name, name)))
+    }
+
+    /// Compound assignment evaluates the target only once:
+    /// globals duplicate the name already on the stack, locals reuse their slot operand.
+    pub fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let line = name.line as u32;
+        let local = self.resolve_local(&name);
+        let slot = local.map(|(slot, _)| slot);
+        if slot.is_none() {
+            if self.is_enclosing_local(&name) {
+                self.error("Can't capture a local variable of an enclosing function; closures are not supported");
+            }
+            self.identifier_constant(name);
+            if self.check(TokenType::Dot) && self.modules.contains_key(self.lexeme(name)) {
+                self.module_receiver = Some(self.lexeme(name).to_string());
+            }
+        }
+        let assigning = can_assign && (self.check(TokenType::Equal) || compound_operator(self.current.token_type).is_some());
+        if assigning && let Some((_, false)) = local {
+            let message = format!("Can't assign to immutable variable '{}'", self.lexeme(name));
+            self.error(&message);
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.parse_precedence(Precedence::Assignment);
+            self.emit_set_variable(slot, line);
+        } else if can_assign && let Some(operator) = compound_operator(self.current.token_type) {
+            self.advance();
+            match slot {
+                Some(slot) => {
+                    self.emit_with(OP_GET_LOCAL, slot, line);
+                },
+                None => {
+                    self.emit(OP_DUP, line);
+                    self.emit(OP_GET_GLOBAL, line);
+                },
+            }
+            self.parse_precedence(Precedence::Assignment);
+            self.emit(operator, line);
+            self.emit_set_variable(slot, line);
+        } else {
+            match slot {
+                Some(slot) => {
+                    self.emit_with(OP_GET_LOCAL, slot, line);
+                },
+                None => self.emit(OP_GET_GLOBAL, line),
+            }
+        }
+    }
+
+    fn emit_set_variable(&mut self, slot: Option<u8>, line: u32) {
+        match slot {
+            Some(slot) => {
+                self.emit_with(OP_SET_LOCAL, slot, line);
+            },
+            None => self.emit(OP_SET_GLOBAL, line),
         }
     }
 
+    pub fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous, can_assign);
+    }
+
     pub fn synchronize(&mut self) {
         self.panic_mode = false;
         
@@ -144,7 +867,8 @@ impl Compiler {
             if self.previous.token_type == TokenType::Semicolon { return; }
             match self.current.token_type {
                 TokenType::For | TokenType::If | TokenType::While | TokenType::Let |
-                TokenType::Fn | TokenType::Item | TokenType::Return => return,
+                TokenType::Fn | TokenType::Item | TokenType::Return | TokenType::Print |
+                TokenType::Break | TokenType::Continue | TokenType::Use | TokenType::Pub => return,
                 _ => {},
             }
 
@@ -152,105 +876,704 @@ impl Compiler {
         }
     }
 
-    pub fn parse_variables(&self) {
-        
-    }
-
-    pub fn number(&mut self) {
+    pub fn number(&mut self, _can_assign: bool) {
         let token = self.previous;
         let lexeme = unsafe {
             std::str::from_utf8_unchecked(std::slice::from_raw_parts(token.start, token.length))
         };
         let value = lexeme.parse::<f64>().expect("Invalid number literal");
-        self.chunk.write_constant(Values::Number(value), token.line as u32);
+        let index = self.chunk.add_constant(Values::Number(value));
+        self.emit_constant_index(index, token.line as u32);
     }
 
-    pub fn grouping(&mut self) {
+    pub fn grouping(&mut self, _can_assign: bool) {
         self.parse_precedence(Precedence::Assignment);
         self.consume(TokenType::RightParen);
     }
 
-    pub fn unary(&mut self) {
+    pub fn unary(&mut self, _can_assign: bool) {
         let operator = self.previous;
         let line = operator.line as u32;
         self.parse_precedence(Precedence::Unary);
 
         match operator.token_type {
-            TokenType::Minus | TokenType::Bang => self.chunk.write_byte(OP_NEGATE, line),
+            TokenType::Minus => self.emit(OP_NEGATE, line),
+            TokenType::Bang => self.emit(OP_NOT, line),
             _ => self.error_at_current(),
         }
     }
 
-    pub fn binary(&mut self) {
+    pub fn binary(&mut self, _can_assign: bool) {
         let operator = self.previous;
         let line = operator.line as u32;
         let rule = get_rule(operator.token_type);
         self.parse_precedence(rule.precedence.next());
 
         match operator.token_type {
-            TokenType::Plus => self.chunk.write_byte(OP_ADD, line),
-            TokenType::Minus => self.chunk.write_byte(OP_SUBTRACT, line),
-            TokenType::Star => self.chunk.write_byte(OP_MULTIPLY, line),
-            TokenType::Slash => self.chunk.write_byte(OP_DIVIDE, line),
-            TokenType::EqualEqual => self.chunk.write_byte(OP_EQUAL, line),
-            TokenType::Greater => self.chunk.write_byte(OP_GREATER, line),
-            TokenType::Less => self.chunk.write_byte(OP_LESS, line),
-            TokenType::BangEqual => {
-                self.chunk.write_byte(OP_EQUAL, line);
-                self.chunk.write_byte(OP_NEGATE, line);
-            },
-            TokenType::GreaterEqual => {
-                self.chunk.write_byte(OP_LESS, line);
-                self.chunk.write_byte(OP_NEGATE, line);
-            },
-            TokenType::LessEqual => {
-                self.chunk.write_byte(OP_GREATER, line);
-                self.chunk.write_byte(OP_NEGATE, line);
-            }
+            TokenType::Plus => self.emit(OP_ADD, line),
+            TokenType::Minus => self.emit(OP_SUBTRACT, line),
+            TokenType::Star => self.emit(OP_MULTIPLY, line),
+            TokenType::Slash => self.emit(OP_DIVIDE, line),
+            TokenType::Percent => self.emit(OP_MODULO, line),
+            TokenType::EqualEqual => self.emit(OP_EQUAL, line),
+            TokenType::Greater => self.emit(OP_GREATER, line),
+            TokenType::Less => self.emit(OP_LESS, line),
+            TokenType::BangEqual => self.emit(OP_NOT_EQUAL, line),
+            TokenType::GreaterEqual => self.emit(OP_GREATER_EQUAL, line),
+            TokenType::LessEqual => self.emit(OP_LESS_EQUAL, line),
+            TokenType::DotDot => self.emit(OP_RANGE, line),
+            TokenType::DotDotEqual => self.emit(OP_RANGE_INCLUSIVE, line),
             _ => self.error_at_current(),
         }
     }
 
-    pub fn literal(&mut self) {
+    pub fn call(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        let arg_count = self.argument_list();
+        self.emit_with(OP_CALL, arg_count, line);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.parse_precedence(Precedence::Assignment);
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments");
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightParen);
+        arg_count.min(u8::MAX as usize) as u8
+    }
+
+    /// Methods are looked up by name at runtime, so the name goes on the stack between the receiver and the arguments.
+    /// Without a call, `a.b` reads an item out of a module.
+    /// `math.pi` and `math.area(2)` on a module bound by `use` must name one of its `pub` items,
+    /// which is checked here; other receivers are only known at runtime.
+    pub fn dot(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        self.consume(TokenType::Identifier);
+        if let Some(module) = self.module_receiver.take() {
+            let item = self.lexeme(self.previous);
+            let items = &self.modules[&module];
+            if !items.exported.contains(item) {
+                let message = if items.defined.contains(item) {
+                    format!("'{}' is private to module '{}'", item, module)
+                } else {
+                    format!("Module '{}' has no item '{}'", module, item)
+                };
+                self.error(&message);
+            }
+        }
+        self.identifier_constant(self.previous);
+        if !self.match_token(TokenType::LeftParen) {
+            self.emit(OP_GET_PROPERTY, line);
+            return;
+        }
+        let arg_count = self.argument_list();
+        self.emit_with(OP_INVOKE, arg_count, line);
+    }
+
+    pub fn list(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        let mut count: usize = 0;
+        // A trailing comma is allowed: `[1, 2,]`.
+        while !self.check(TokenType::RightBracket) {
+            self.parse_precedence(Precedence::Assignment);
+            if count == u8::MAX as usize {
+                self.error("Can't have more than 255 elements in a list literal");
+            }
+            count += 1;
+            if !self.match_token(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightBracket);
+        self.emit_with(OP_BUILD_LIST, count.min(u8::MAX as usize) as u8, line);
+    }
+
+    /// `{` in expression position: `{}` and `{key: value, ...}` are maps, anything else is a block
+    /// whose value is its last expression. The first item is compiled before it is known which one
+    /// this is, and a `:` right after it makes it the first key.
+    /// (A `{` starting a statement is always a block statement and never reaches this.)
+    pub fn brace(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        if self.match_token(TokenType::RightBrace) {
+            self.emit_with(OP_BUILD_MAP, 0, line);
+            return;
+        }
+
+        let base_depth = self.stack_depth;
+        self.begin_scope();
+        if starts_statement(self.current.token_type) {
+            self.block_expression_items(base_depth, false);
+            return;
+        }
+        self.parse_precedence(Precedence::Assignment);
+        if self.match_token(TokenType::Colon) {
+            self.scope_depth -= 1;
+            self.map_entries(line);
+        } else {
+            self.block_expression_items(base_depth, true);
+        }
+    }
+
+    /// The rest of a map literal, after its first key and `:`.
+    fn map_entries(&mut self, line: u32) {
+        let mut count: usize = 0;
+        loop {
+            self.parse_precedence(Precedence::Assignment);
+            if count == u8::MAX as usize {
+                self.error("Can't have more than 255 entries in a map literal");
+            }
+            count += 1;
+            // A trailing comma is allowed: `{"a": 1,}`.
+            if !self.match_token(TokenType::Comma) || self.check(TokenType::RightBrace) { break; }
+            self.parse_precedence(Precedence::Assignment);
+            self.consume(TokenType::Colon);
+        }
+        self.consume(TokenType::RightBrace);
+        self.emit_with(OP_BUILD_MAP, count.min(u8::MAX as usize) as u8, line);
+    }
+
+    /// A block after its `{`, leaving the value of its last expression (or nil) on the stack.
+    /// The scope is already begun and `base_depth` is the stack depth outside it;
+    /// `first_parsed` means the first expression has just been compiled by `brace`.
+    fn block_expression_items(&mut self, base_depth: usize, mut first_parsed: bool) {
+        let locals = self.locals.len();
+        let mut has_value = false;
+
+        while first_parsed || (!self.check(TokenType::RightBrace) && !self.check(TokenType::Eof)) {
+            if has_value {
+                self.emit(OP_POP, self.previous.line as u32);
+                has_value = false;
+            }
+            if !first_parsed && starts_statement(self.current.token_type) {
+                self.declaration();
+                continue;
+            }
+
+            let block_like = if first_parsed {
+                first_parsed = false;
+                self.previous.token_type == TokenType::RightBrace
+            } else {
+                let starts_block_like = matches!(self.current.token_type, TokenType::If | TokenType::Match | TokenType::Try | TokenType::LeftBrace);
+                self.parse_precedence(Precedence::Assignment);
+                starts_block_like && self.previous.token_type == TokenType::RightBrace
+            };
+            // `if`, `match`, `try` and blocks can stand as statements without a `;`; their value is
+            // popped unless they turn out to be the last thing in the block.
+            if self.match_token(TokenType::Semicolon) {
+                self.emit(OP_POP, self.previous.line as u32);
+            } else if block_like || self.check(TokenType::RightBrace) {
+                has_value = true;
+            } else {
+                self.consume(TokenType::Semicolon);
+            }
+            if self.panic_mode { self.synchronize(); }
+        }
+        self.consume(TokenType::RightBrace);
+        let line = self.previous.line as u32;
+        if !has_value {
+            self.emit(OP_NIL, line);
+        }
+
+        // Move the value down over the block's locals, then drop them.
+        // Saturating, because the depth is unreliable after a compile error.
+        let extra = self.stack_depth.saturating_sub(base_depth + 1);
+        if extra > 0 {
+            if base_depth > u8::MAX as usize {
+                self.error("Too many values on the stack for a block");
+            }
+            self.emit_with(OP_SET_LOCAL, base_depth as u8, line);
+            for _ in 0..extra { self.emit(OP_POP, line); }
+        }
+        self.scope_depth -= 1;
+        self.locals.truncate(locals);
+    }
+
+    /// `if` in expression position: both branches are blocks that leave a value, and a missing
+    /// `else` gives nil.
+    pub fn if_expression(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        self.parse_precedence(Precedence::Assignment);
+
+        let else_jump = self.emit_jump(OP_JUMP_IF_FALSE, line);
+        let else_depth = self.stack_depth;
+        self.emit(OP_POP, line);
+        self.branch_block();
+        let end_jump = self.emit_jump(OP_JUMP, line);
+
+        self.patch_jump(else_jump);
+        self.stack_depth = else_depth;
+        self.emit(OP_POP, line);
+        if self.match_token(TokenType::Else) {
+            if self.match_token(TokenType::If) {
+                self.if_expression(false);
+            } else {
+                self.branch_block();
+            }
+        } else {
+            self.emit(OP_NIL, line);
+        }
+        self.patch_jump(end_jump);
+    }
+
+    /// A branch of an `if` expression, which is always a block, even when it is `{}`.
+    fn branch_block(&mut self) {
+        self.consume(TokenType::LeftBrace);
+        let base_depth = self.stack_depth;
+        self.begin_scope();
+        self.block_expression_items(base_depth, false);
+    }
+
+    /// `try { ... } catch e { ... }` is the value of whichever block finishes; `catch { ... }` binds nothing.
+    /// `OP_TRY` makes the catch block the handler for errors raised before the matching `OP_END_TRY`:
+    /// a `?` on an `Err` in this function, or a runtime error here or in anything it calls.
+    /// The VM then cuts the stack back to its depth at `OP_TRY` and pushes the error, which `e` names.
+    pub fn try_expression(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        let base_depth = self.stack_depth;
+        if base_depth > u8::MAX as usize {
+            self.error("Too many values on the stack for a try");
+        }
+
+        let catch_jump = self.emit_jump(OP_TRY, line);
+        self.try_depth += 1;
+        self.branch_block();
+        self.try_depth -= 1;
+        self.emit(OP_END_TRY, line);
+        let end_jump = self.emit_jump(OP_JUMP, line);
+
+        self.patch_jump(catch_jump);
+        self.stack_depth = base_depth + 1;
+        self.consume(TokenType::Catch);
+        let locals = self.locals.len();
+        self.begin_scope();
+        if self.match_token(TokenType::Identifier) {
+            self.declare_local_at(self.previous, base_depth, false);
+            self.mark_initialized();
+        }
+        self.branch_block();
+        // Like a match binding, the error's slot is where the result goes, so nothing is popped for it.
+        self.scope_depth -= 1;
+        self.locals.truncate(locals);
+        self.emit_with(OP_SET_LOCAL, base_depth as u8, line);
+        self.emit(OP_POP, line);
+        self.patch_jump(end_jump);
+    }
+
+    /// `assert(condition)`, `assert(condition, message)`, `assert_eq(left, right)` and `panic(message)`.
+    /// They read like calls but compile to one instruction each, which is nil when the check passes.
+    pub fn builtin(&mut self, _can_assign: bool) {
+        let keyword = self.previous;
+        let line = keyword.line as u32;
+        self.consume(TokenType::LeftParen);
+        let arg_count = self.argument_list();
+        match keyword.token_type {
+            TokenType::Assert if (1..=2).contains(&arg_count) => self.emit_with(OP_ASSERT, arg_count, line),
+            TokenType::Assert => self.error("'assert' takes a condition and an optional message"),
+            TokenType::AssertEq if arg_count == 2 => self.emit(OP_ASSERT_EQ, line),
+            TokenType::AssertEq => self.error("'assert_eq' takes two values to compare"),
+            _ if arg_count <= 1 => self.emit_with(OP_PANIC, arg_count, line),
+            _ => self.error("'panic' takes an optional message"),
+        }
+    }
+
+    /// `Ok(value)` and `Err(error)`.
+    pub fn result_value(&mut self, _can_assign: bool) {
+        let constructor = self.previous;
+        self.consume(TokenType::LeftParen);
+        self.parse_precedence(Precedence::Assignment);
+        self.consume(TokenType::RightParen);
+        let opcode = if constructor.token_type == TokenType::Ok { OP_OK } else { OP_ERR };
+        self.emit(opcode, constructor.line as u32);
+    }
+
+    /// Postfix `?`: unwraps an `Ok`, and hands an `Err` to the enclosing `try` in this function,
+    /// or else returns it from the function. At the top level of a file there is nothing to return
+    /// to, so an `Err` that reaches it there is a runtime error.
+    pub fn propagate(&mut self, _can_assign: bool) {
+        self.emit(OP_PROPAGATE, self.previous.line as u32);
+    }
+
+    /// `xs[i] op= v` keeps one copy of the target and index for the read and one for the write.
+    pub fn index(&mut self, can_assign: bool) {
+        let line = self.previous.line as u32;
+        self.parse_precedence(Precedence::Assignment);
+        self.consume(TokenType::RightBracket);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.parse_precedence(Precedence::Assignment);
+            self.emit(OP_SET_INDEX, line);
+        } else if can_assign && let Some(operator) = compound_operator(self.current.token_type) {
+            self.advance();
+            self.emit(OP_DUP2, line);
+            self.emit(OP_GET_INDEX, line);
+            self.parse_precedence(Precedence::Assignment);
+            self.emit(operator, line);
+            self.emit(OP_SET_INDEX, line);
+        } else {
+            self.emit(OP_GET_INDEX, line);
+        }
+    }
+
+    /// `match value { pattern => expression, ... }`. The value stays on the stack while the arms
+    /// test it in order; each arm's result is written over it once the arm is chosen.
+    /// Without a catch-all arm, falling off the last one raises a runtime error.
+    pub fn match_expression(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        self.parse_precedence(Precedence::Assignment);
+        let value_slot = self.stack_depth - 1;
+        if value_slot > u8::MAX as usize {
+            self.error("Too many values on the stack for a match");
+        }
+        self.consume(TokenType::LeftBrace);
+
+        let mut end_jumps = Vec::new();
+        let mut exhaustive = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let (body_jumps, binding) = self.match_pattern(line);
+            exhaustive = body_jumps.is_empty();
+            let next_arm = (!exhaustive).then(|| self.emit_jump(OP_JUMP, line));
+            for jump in body_jumps { self.patch_jump(jump); }
+
+            self.consume(TokenType::FatArrow);
+            let locals = self.locals.len();
+            self.begin_scope();
+            if let Some(name) = binding {
+                self.declare_local_at(name, value_slot, false);
+                self.mark_initialized();
+            }
+            self.parse_precedence(Precedence::Assignment);
+            // The binding names the matched value's slot, so leaving the arm pops nothing.
+            self.scope_depth -= 1;
+            self.locals.truncate(locals);
+            end_jumps.push(self.emit_jump(OP_JUMP, line));
+            self.stack_depth = value_slot + 1;
+
+            if let Some(jump) = next_arm { self.patch_jump(jump); }
+            if !self.match_token(TokenType::Comma) { break; }
+            if exhaustive && !self.check(TokenType::RightBrace) {
+                self.error("Arms after a catch-all pattern can never match");
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace);
+
+        if !exhaustive {
+            self.emit(OP_NO_MATCH, line);
+        }
+        self.stack_depth = value_slot + 2;
+        for jump in end_jumps { self.patch_jump(jump); }
+        self.emit_with(OP_SET_LOCAL, value_slot as u8, line);
+        self.emit(OP_POP, line);
+    }
+
+    /// Compiles `alternative | alternative ...` against the value on top of the stack and returns
+    /// the jumps taken on a match, plus the name an identifier pattern binds.
+    /// A wildcard or identifier always matches, which is signalled by returning no jumps:
+    /// the code after it is the arm body. Failing every alternative falls through.
+    fn match_pattern(&mut self, line: u32) -> (Vec<usize>, Option<Token>) {
+        let mut body_jumps = Vec::new();
+        loop {
+            if self.match_token(TokenType::Identifier) {
+                let name = self.previous;
+                let is_wildcard = name.length == 1 && unsafe { *name.start } == b'_';
+                if !body_jumps.is_empty() || self.check(TokenType::Pipe) {
+                    self.error("Can't combine a catch-all pattern with '|'");
+                }
+                return (Vec::new(), (!is_wildcard).then_some(name));
+            }
+
+            self.emit(OP_DUP, line);
+            self.pattern_literal();
+            if self.match_token(TokenType::DotDot) || self.match_token(TokenType::DotDotEqual) {
+                let inclusive = self.previous.token_type == TokenType::DotDotEqual;
+                self.pattern_literal();
+                self.emit_with(OP_MATCH_RANGE, inclusive as u8, line);
+            } else {
+                self.emit(OP_EQUAL, line);
+            }
+
+            let next = self.emit_jump(OP_JUMP_IF_FALSE, line);
+            self.emit(OP_POP, line);
+            body_jumps.push(self.emit_jump(OP_JUMP, line));
+            self.stack_depth += 1;
+            self.patch_jump(next);
+            self.emit(OP_POP, line);
+
+            if !self.match_token(TokenType::Pipe) { return (body_jumps, None); }
+        }
+    }
+
+    /// A number (optionally negative), plain string, `true`, `false` or `nil`.
+    fn pattern_literal(&mut self) {
+        self.advance();
+        match self.previous.token_type {
+            TokenType::Number => self.number(false),
+            TokenType::String => self.string(false),
+            TokenType::True | TokenType::False | TokenType::Nil => self.literal(false),
+            TokenType::Minus => {
+                let line = self.previous.line as u32;
+                self.consume(TokenType::Number);
+                self.number(false);
+                self.emit(OP_NEGATE, line);
+            },
+            _ => self.error("Expected a literal pattern"),
+        }
+    }
+
+    pub fn literal(&mut self, _can_assign: bool) {
         let literal = self.previous;
         let line = literal.line as u32;
 
         match literal.token_type {
-            TokenType::True => self.chunk.write_byte(OP_TRUE, line),
-            TokenType::False => self.chunk.write_byte(OP_FALSE, line),
-            TokenType::Nil => self.chunk.write_byte(OP_NIL, line),
+            TokenType::True => self.emit(OP_TRUE, line),
+            TokenType::False => self.emit(OP_FALSE, line),
+            TokenType::Nil => self.emit(OP_NIL, line),
             _ => self.error_at_current(),
         }
     }
 
-    pub fn string(&mut self) {
-        let token = self.previous;
-        let obj_ptr: *mut Obj = unsafe { make_obj_str(token.start.add(1), token.length - 2) } as *mut Obj;
-        self.chunk.write_constant(Values::Obj(obj_ptr), token.line as u32);
+    pub fn string(&mut self, _can_assign: bool) {
+        self.string_segment(self.previous);
+    }
+
+    /// `"a" + x + y` concatenates from the first `+` on, so the chain is pushed part by part
+    /// and joined by one `OP_BUILD_STRING` instead of allocating every intermediate string.
+    /// The string on the left is already on the stack as the first part.
+    fn concatenation(&mut self) {
+        let line = self.current.line as u32;
+        let mut parts: u8 = 1;
+        while self.match_token(TokenType::Plus) {
+            if parts == u8::MAX {
+                self.emit_with(OP_BUILD_STRING, parts, line);
+                parts = 1;
+            }
+            self.parse_precedence(Precedence::Factor);
+            parts += 1;
+        }
+        self.emit_with(OP_BUILD_STRING, parts, line);
+    }
+
+    /// `"a {x} b {y}"` arrives as `Interpolation("a {)`, x, `Interpolation(} b {)`, y, `String(}")`.
+    /// Every piece is pushed in order and `OP_BUILD_STRING` joins them; empty text pieces are skipped.
+    pub fn interpolation(&mut self, _can_assign: bool) {
+        let line = self.previous.line as u32;
+        let mut parts: usize = 0;
+        loop {
+            if self.previous.length > 2 {
+                self.string_segment(self.previous);
+                parts += 1;
+            }
+            self.parse_precedence(Precedence::Assignment);
+            parts += 1;
+            if !self.match_token(TokenType::Interpolation) { break; }
+        }
+        self.consume(TokenType::String);
+        if self.previous.length > 2 {
+            self.string_segment(self.previous);
+            parts += 1;
+        }
+
+        if parts > u8::MAX as usize {
+            self.error("Too many parts in one interpolated string");
+            return;
+        }
+        self.emit_with(OP_BUILD_STRING, parts as u8, line);
+    }
+
+    /// Loads the text between a string token's delimiters (quotes or interpolation braces), turning `\{` into `{`.
+    fn string_segment(&mut self, token: Token) {
+        let text = unsafe { std::slice::from_raw_parts(token.start.add(1), token.length - 2) };
+        let index = if text.windows(2).any(|pair| pair == b"\\{") {
+            let mut unescaped = Vec::with_capacity(text.len());
+            for (i, &byte) in text.iter().enumerate() {
+                if !(byte == b'\\' && text.get(i + 1) == Some(&b'{')) { unescaped.push(byte); }
+            }
+            self.chunk.add_string_constant(unescaped.as_ptr(), unescaped.len())
+        } else {
+            self.chunk.add_string_constant(text.as_ptr(), text.len())
+        };
+        self.emit_constant_index(index, token.line as u32);
+    }
+}
+
+pub const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+pub fn compound_operator(token_type: TokenType) -> Option<u8> {
+    match token_type {
+        TokenType::PlusEqual => Some(OP_ADD),
+        TokenType::MinusEqual => Some(OP_SUBTRACT),
+        TokenType::StarEqual => Some(OP_MULTIPLY),
+        TokenType::SlashEqual => Some(OP_DIVIDE),
+        TokenType::PercentEqual => Some(OP_MODULO),
+        _ => None,
+    }
+}
+
+/// Tokens that begin a statement rather than an expression inside a block expression.
+fn starts_statement(token_type: TokenType) -> bool {
+    matches!(token_type,
+        TokenType::Let | TokenType::Fn | TokenType::Print | TokenType::Return |
+        TokenType::While | TokenType::For | TokenType::Break | TokenType::Continue |
+        TokenType::Use | TokenType::Pub)
+}
+
+/// Compiles the `.rf` file at `path`; `use` paths inside it resolve against its directory.
+/// `Ok(None)` means the file was read but had compile errors, which have been reported.
+pub fn compile_file(path: &Path, opt_level: OptLevel) -> io::Result<Option<Chunk>> {
+    let mut bytes = fs::read(path)?;
+    bytes.push(b'\0');
+    let lexer = Lexer::new(bytes.as_slice());
+    let mut compiler = Compiler::new(lexer, opt_level);
+    compiler.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok(compiler.compile())
+}
+
+/// Scans a module file's tokens for its top-level items; whether it compiles is up to `OP_IMPORT`.
+fn module_items(path: &Path) -> io::Result<ModuleItems> {
+    let mut bytes = fs::read(path)?;
+    bytes.push(b'\0');
+    let mut lexer = Lexer::new(bytes.as_slice());
+    let mut items = ModuleItems { defined: HashSet::new(), exported: HashSet::new() };
+    let (mut depth, mut public, mut declaring) = (0usize, false, false);
+    loop {
+        let token = lexer.scan_token();
+        match token.token_type {
+            TokenType::Eof => break,
+            TokenType::Pub if depth == 0 => {
+                public = true;
+                continue;
+            },
+            TokenType::Let | TokenType::Fn if depth == 0 => {
+                declaring = true;
+                continue;
+            },
+            TokenType::Mut if declaring => continue,
+            TokenType::Identifier if declaring => {
+                let name = unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(token.start, token.length)) };
+                if public { items.exported.insert(name.to_string()); }
+                items.defined.insert(name.to_string());
+            },
+            TokenType::LeftBrace => depth += 1,
+            TokenType::RightBrace => depth = depth.saturating_sub(1),
+            _ => {},
+        }
+        public = false;
+        declaring = false;
+    }
+    Ok(items)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Every function starts with slot 0 taken by the callee; the empty name can never be looked up.
+fn function_locals() -> Vec<Local> {
+    let mut locals = Vec::with_capacity(UINT8_COUNT);
+    locals.push(Local { name: Token::dummy(), depth: Some(0), slot: 0, mutable: false });
+    locals
+}
+
+pub fn identifiers_equal(a: &Token, b: &Token) -> bool {
+    a.length == b.length && unsafe {
+        std::slice::from_raw_parts(a.start, a.length) == std::slice::from_raw_parts(b.start, b.length)
     }
 }
 
 pub unsafe fn make_obj_str(start: *const u8, length: usize) -> *mut ObjString {
-    let str_layout = Layout::array::<u8>(length + 1).unwrap();
-    let chars_ptr: *mut u8 = unsafe { alloc::alloc(str_layout) };
-   
     unsafe {
-        std::ptr::copy_nonoverlapping(start, chars_ptr, length);
+        let obj_ptr = alloc_obj_str(length);
+        std::ptr::copy_nonoverlapping(start, (*obj_ptr).chars, length);
+        obj_ptr
     }
+}
 
-    let obj_str_layout = Layout::new::<ObjString>();
-    let obj_ptr = unsafe { alloc::alloc(obj_str_layout) as *mut ObjString };
-    let hash = hash::fnv1a_hash(chars_ptr, length);
-
+/// Allocates a string of exactly `length` bytes, header included, in a single allocation.
+/// The caller must fill all `length` bytes of `chars` with valid UTF-8 before the string is read.
+pub unsafe fn alloc_obj_str(length: usize) -> *mut ObjString {
+    let (layout, offset) = ObjString::layout(length);
     unsafe {
+        let ptr = alloc::alloc(layout);
+        if ptr.is_null() { alloc::handle_alloc_error(layout); }
+
+        let obj_ptr = ptr as *mut ObjString;
         std::ptr::write(obj_ptr, ObjString {
             obj: Obj { type_obj: ObjType::String, next: std::ptr::null_mut(), },
             length,
-            chars: chars_ptr,
-            hash,
+            chars: ptr.add(offset),
+            hash: None,
         });
+        obj_ptr
     }
+}
 
-    obj_ptr
+
+pub fn make_obj_map(table: Table) -> *mut ObjMap {
+    Box::into_raw(Box::new(ObjMap {
+        obj: Obj { type_obj: ObjType::Map, next: std::ptr::null_mut(), },
+        table,
+    }))
+}
+
+pub fn make_obj_range(start: f64, end: f64, inclusive: bool) -> *mut ObjRange {
+    Box::into_raw(Box::new(ObjRange {
+        obj: Obj { type_obj: ObjType::Range, next: std::ptr::null_mut(), },
+        start,
+        end,
+        inclusive,
+    }))
+}
+
+pub fn make_obj_iterator(state: IterState) -> *mut ObjIterator {
+    Box::into_raw(Box::new(ObjIterator {
+        obj: Obj { type_obj: ObjType::Iterator, next: std::ptr::null_mut(), },
+        state,
+    }))
+}
+
+pub fn make_obj_result(ok: bool, value: Values) -> *mut ObjResult {
+    Box::into_raw(Box::new(ObjResult {
+        obj: Obj { type_obj: ObjType::Result, next: std::ptr::null_mut(), },
+        ok,
+        value,
+    }))
+}
+
+pub fn make_obj_list(items: Vec<Values>) -> *mut ObjList {
+    Box::into_raw(Box::new(ObjList {
+        obj: Obj { type_obj: ObjType::List, next: std::ptr::null_mut(), },
+        items,
+    }))
 }
 
+/// `name` is null for the top-level script; otherwise the function owns it.
+pub fn make_obj_function(name: *mut ObjString, arity: u8, chunk: Chunk) -> *mut ObjFunction {
+    Box::into_raw(Box::new(ObjFunction {
+        obj: Obj { type_obj: ObjType::Function, next: std::ptr::null_mut(), },
+        arity,
+        max_stack: 0,
+        chunk,
+        name,
+        module: std::ptr::null_mut(),
+    }))
+}
+
+/// `name` is owned by the module; `path` stays owned by the chunk that imported it.
+pub fn make_obj_module(name: *mut ObjString, path: *mut ObjString) -> *mut ObjModule {
+    Box::into_raw(Box::new(ObjModule {
+        obj: Obj { type_obj: ObjType::Module, next: std::ptr::null_mut(), },
+        name,
+        path,
+        globals: Table::new(),
+        immutable_globals: Table::new(),
+        exports: Table::new(),
+        loaded: false,
+    }))
+}
@@ -3,14 +3,15 @@
 pub enum Precedence {
     None = 0,
     Assignment,  // =
+    Range,       // .. ..=
     Or,          // or
     And,         // and
     Equality,    // == !=
     Comparison,  // < > <= >=
     Term,        // + -
-    Factor,      // * /
+    Factor,      // * / %
     Unary,       // ! -
-    Call,        // . ()
+    Call,        // . () [] ?
     Primary,
 }
 
@@ -18,7 +19,8 @@ pub enum Precedence {
 impl Precedence {
     pub fn next(self) -> Self {
         match self {
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Range,
+            Precedence::Range => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
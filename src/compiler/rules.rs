@@ -5,25 +5,24 @@ use std::sync::OnceLock;
 
 #[derive(Clone, Copy)]
 pub struct ParseRule {
-    pub prefix: Option<fn(&mut Compiler)>,
-    pub infix: Option<fn(&mut Compiler)>,
+    pub prefix: Option<fn(&mut Compiler, bool)>,
+    pub infix: Option<fn(&mut Compiler, bool)>,
     pub precedence: Precedence,
 }
 
 /// == Precedence ==
 /// None = 0,
 /// Assignment, // =
+/// Range,      // .. ..=
 /// Or,         // or
 /// And,        // and
 /// Equality,   // == !=
 /// Comparison, // < > <= >=
 /// Term,       // + -
-/// Factor,     // * /
+/// Factor,     // * / %
 /// Unary,      // ! -
-/// Call,       // . ()
+/// Call,       // . () [] ?
 /// Primary,    //
-
-
 static RULES: OnceLock<[ParseRule; 256]> = OnceLock::new();
 
 pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
@@ -36,8 +35,8 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
 
         rules[TokenType::LeftParen as usize] = ParseRule {
             prefix: Some(Compiler::grouping),
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(Compiler::call),
+            precedence: Precedence::Call,
         };
 
         rules[TokenType::RightParen as usize] = ParseRule {
@@ -47,7 +46,7 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
         };
 
         rules[TokenType::LeftBrace as usize] = ParseRule {
-            prefix: None,
+            prefix: Some(Compiler::brace),
             infix: None,
             precedence: Precedence::None,
         };
@@ -58,18 +57,30 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
             precedence: Precedence::None,
         };
 
-        rules[TokenType::Comma as usize] = ParseRule {
+        rules[TokenType::LeftBracket as usize] = ParseRule {
+            prefix: Some(Compiler::list),
+            infix: Some(Compiler::index),
+            precedence: Precedence::Call,
+        };
+
+        rules[TokenType::RightBracket as usize] = ParseRule {
             prefix: None,
             infix: None,
             precedence: Precedence::None,
         };
 
-        rules[TokenType::Dot as usize] = ParseRule {
+        rules[TokenType::Comma as usize] = ParseRule {
             prefix: None,
             infix: None,
             precedence: Precedence::None,
         };
 
+        rules[TokenType::Dot as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Compiler::dot),
+            precedence: Precedence::Call,
+        };
+
         rules[TokenType::Minus as usize] = ParseRule {
             prefix: Some(Compiler::unary),
             infix: Some(Compiler::binary),
@@ -100,6 +111,12 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
             precedence: Precedence::Factor,
         };
 
+        rules[TokenType::Percent as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Compiler::binary),
+            precedence: Precedence::Factor,
+        };
+
         rules[TokenType::Bang as usize] = ParseRule {
             prefix: Some(Compiler::unary),
             infix: None,
@@ -148,18 +165,66 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
             precedence: Precedence::Comparison,
         };
 
-        rules[TokenType::Identifier as usize] = ParseRule {
+        rules[TokenType::PlusEqual as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::MinusEqual as usize] = ParseRule {
             prefix: None,
             infix: None,
             precedence: Precedence::None,
         };
 
+        rules[TokenType::StarEqual as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::SlashEqual as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::PercentEqual as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::DotDot as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Compiler::binary),
+            precedence: Precedence::Range,
+        };
+
+        rules[TokenType::DotDotEqual as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Compiler::binary),
+            precedence: Precedence::Range,
+        };
+
+        rules[TokenType::Identifier as usize] = ParseRule {
+            prefix: Some(Compiler::variable),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
         rules[TokenType::String as usize] = ParseRule {
             prefix: Some(Compiler::string),
             infix: None,
             precedence: Precedence::None,
         };
 
+        rules[TokenType::Interpolation as usize] = ParseRule {
+            prefix: Some(Compiler::interpolation),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
         rules[TokenType::Number as usize] = ParseRule {
             prefix: Some(Compiler::number),
             infix: None,
@@ -191,6 +256,12 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
         };
 
         rules[TokenType::If as usize] = ParseRule {
+            prefix: Some(Compiler::if_expression),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::In as usize] = ParseRule {
             prefix: None,
             infix: None,
             precedence: Precedence::None,
@@ -256,6 +327,60 @@ pub fn get_rule(token_type: TokenType) -> &'static ParseRule {
             precedence: Precedence::None,
         };
 
+        rules[TokenType::Print as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Match as usize] = ParseRule {
+            prefix: Some(Compiler::match_expression),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Assert as usize] = ParseRule {
+            prefix: Some(Compiler::builtin),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::AssertEq as usize] = ParseRule {
+            prefix: Some(Compiler::builtin),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Panic as usize] = ParseRule {
+            prefix: Some(Compiler::builtin),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Try as usize] = ParseRule {
+            prefix: Some(Compiler::try_expression),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Ok as usize] = ParseRule {
+            prefix: Some(Compiler::result_value),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Err as usize] = ParseRule {
+            prefix: Some(Compiler::result_value),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Question as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Compiler::propagate),
+            precedence: Precedence::Call,
+        };
+
         rules[TokenType::Error as usize] = ParseRule {
             prefix: None,
             infix: None,
@@ -6,6 +6,9 @@ pub struct Lexer {
     start: *const u8,
     current: *const u8,
     line: usize,
+    /// One entry per string interpolation being scanned, counting the `{` opened inside it,
+    /// so the `}` that resumes the string can be told apart from one that closes a block.
+    interpolations: Vec<usize>,
 }
 
 impl Lexer {
@@ -15,6 +18,7 @@ impl Lexer {
             start: ptr,
             current: ptr,
             line: 1,
+            interpolations: Vec::new(),
         }
     }
 
@@ -43,15 +47,79 @@ impl Lexer {
         match self.advance() {
             b'(' => return self.make_token(TokenType::LeftParen),
             b')' => return self.make_token(TokenType::RightParen),
-            b'{' => return self.make_token(TokenType::LeftBrace),
-            b'}' => return self.make_token(TokenType::RightBrace),
+            b'{' => {
+                if let Some(open) = self.interpolations.last_mut() { *open += 1; }
+                return self.make_token(TokenType::LeftBrace);
+            },
+            b'}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    return self.make_string_token();
+                },
+                Some(open) => {
+                    *open -= 1;
+                    return self.make_token(TokenType::RightBrace);
+                },
+                None => return self.make_token(TokenType::RightBrace),
+            },
+            b'[' => return self.make_token(TokenType::LeftBracket),
+            b']' => return self.make_token(TokenType::RightBracket),
             b',' => return self.make_token(TokenType::Comma),
-            b'.' => return self.make_token(TokenType::Dot),
-            b'-' => return self.make_token(TokenType::Minus),
-            b'+' => return self.make_token(TokenType::Plus),
+            b':' => {
+                if self.match_byte(b':') {
+                    return self.make_token(TokenType::ColonColon);
+                } else {
+                    return self.make_token(TokenType::Colon);
+                }
+            },
+            b'|' => return self.make_token(TokenType::Pipe),
+            b'?' => return self.make_token(TokenType::Question),
+            b'.' => {
+                if !self.match_byte(b'.') {
+                    return self.make_token(TokenType::Dot);
+                }
+                if self.match_byte(b'=') {
+                    return self.make_token(TokenType::DotDotEqual);
+                } else {
+                    return self.make_token(TokenType::DotDot);
+                }
+            },
             b';' => return self.make_token(TokenType::Semicolon),
-            b'/' => return self.make_token(TokenType::Slash),
-            b'*' => return self.make_token(TokenType::Star),
+            b'-' => {
+                if self.match_byte(b'=') {
+                    return self.make_token(TokenType::MinusEqual);
+                } else {
+                    return self.make_token(TokenType::Minus);
+                }
+            },
+            b'+' => {
+                if self.match_byte(b'=') {
+                    return self.make_token(TokenType::PlusEqual);
+                } else {
+                    return self.make_token(TokenType::Plus);
+                }
+            },
+            b'/' => {
+                if self.match_byte(b'=') {
+                    return self.make_token(TokenType::SlashEqual);
+                } else {
+                    return self.make_token(TokenType::Slash);
+                }
+            },
+            b'*' => {
+                if self.match_byte(b'=') {
+                    return self.make_token(TokenType::StarEqual);
+                } else {
+                    return self.make_token(TokenType::Star);
+                }
+            },
+            b'%' => {
+                if self.match_byte(b'=') {
+                    return self.make_token(TokenType::PercentEqual);
+                } else {
+                    return self.make_token(TokenType::Percent);
+                }
+            },
             b'!' => {
                 if self.match_byte(b'=') {
                     return self.make_token(TokenType::BangEqual);
@@ -62,6 +130,8 @@ impl Lexer {
             b'=' => {
                 if self.match_byte(b'=') {
                     return self.make_token(TokenType::EqualEqual);
+                } else if self.match_byte(b'>') {
+                    return self.make_token(TokenType::FatArrow);
                 } else {
                     return self.make_token(TokenType::Equal);
                 }
@@ -88,22 +158,20 @@ impl Lexer {
     }
 
     pub fn is_end(&self) -> bool {
-        unsafe { *self.current == (b'\0' as u8) }
+        unsafe { *self.current == b'\0' }
     }
 
     pub fn is_digit(&self, idx: usize) -> bool {
         unsafe {
             let byte = *self.current.add(idx);
-            byte >= b'0' && byte <= b'9'
+            byte.is_ascii_digit()
         }
     }
 
     pub fn is_alpha(&self) -> bool {
         unsafe {
             let byte = *self.current;
-            (byte >= b'a' && byte <= b'z') ||
-            (byte >= b'A' && byte <= b'Z') ||
-            byte == b'_'
+            byte.is_ascii_alphabetic() || byte == b'_'
         }
     }
 
@@ -127,26 +195,34 @@ impl Lexer {
 
     #[inline(always)]
     pub fn skip_whitespace(&mut self) {
-        while matches!(self.peek(), b' ' | b'\r' | b'\t' | b'\n' | b'/') {
-            let mut byte = unsafe { *self.current };
-            if byte == b'\n' {
-                self.line += 1;
-            } else if self.peek_next() == b'/' {
-                while (byte != b'\n') && !self.is_end() {
+        loop {
+            match self.peek() {
+                b' ' | b'\r' | b'\t' => { self.advance(); },
+                b'\n' => {
+                    self.line += 1;
                     self.advance();
-                    byte = unsafe { *self.current };
-                }
-                self.line += 1;
+                },
+                b'/' if self.peek_next() == b'/' => {
+                    while self.peek() != b'\n' && !self.is_end() { self.advance(); }
+                },
+                _ => return,
             }
-            self.advance();
         }
-
     }
 
+    /// Scans from an opening `"` (or the `}` ending an interpolated expression) up to the closing `"`,
+    /// or up to a `{`, which yields an `Interpolation` token and switches back to ordinary tokens.
+    /// `\{` keeps a literal brace.
     pub fn make_string_token(&mut self) -> Token {
         while !self.is_end() {
             let byte = unsafe { *self.current };
             if byte == b'"' { break; }
+            if byte == b'{' {
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
+            }
+            if byte == b'\\' && self.peek_next() == b'{' { self.advance(); }
             if byte == b'\n' { self.line += 1; }
             self.advance();
         }
@@ -178,17 +254,72 @@ impl Lexer {
 
         let first = unsafe { *start };
         match first {
-            b'a' => self.check_keyword(start, len, b"and", TokenType::And),
+            b'a' => match len {
+                3 => self.check_keyword(start, len, b"and", TokenType::And),
+                6 => self.check_keyword(start, len, b"assert", TokenType::Assert),
+                9 => self.check_keyword(start, len, b"assert_eq", TokenType::AssertEq),
+                _ => TokenType::Identifier,
+            },
+            b'b' => self.check_keyword(start, len, b"break", TokenType::Break),
+            b'c' => {
+                if len >= 2 {
+                    let second = unsafe { *start.add(1) };
+                    match second {
+                        b'a' => self.check_keyword(start, len, b"catch", TokenType::Catch),
+                        b'o' => self.check_keyword(start, len, b"continue", TokenType::Continue),
+                        _ => TokenType::Identifier,
+                    }
+                } else { TokenType::Identifier }
+            },
             b'e' => self.check_keyword(start, len, b"else", TokenType::Else),
-            b'i' => self.check_keyword(start, len, b"if", TokenType::If),
+            b'i' => {
+                if len == 2 {
+                    let second = unsafe { *start.add(1) };
+                    match second {
+                        b'f' => TokenType::If,
+                        b'n' => TokenType::In,
+                        _ => TokenType::Identifier,
+                    }
+                } else { TokenType::Identifier }
+            },
             b'l' => self.check_keyword(start, len, b"let", TokenType::Let),
+            b'm' => {
+                if len >= 2 {
+                    let second = unsafe { *start.add(1) };
+                    match second {
+                        b'a' => self.check_keyword(start, len, b"match", TokenType::Match),
+                        b'u' => self.check_keyword(start, len, b"mut", TokenType::Mut),
+                        _ => TokenType::Identifier,
+                    }
+                } else { TokenType::Identifier }
+            },
             b'n' => self.check_keyword(start, len, b"nil", TokenType::Nil),
             b'o' => self.check_keyword(start, len, b"or", TokenType::Or),
+            b'p' => {
+                if len >= 2 {
+                    let second = unsafe { *start.add(1) };
+                    match second {
+                        b'a' => self.check_keyword(start, len, b"panic", TokenType::Panic),
+                        b'r' => self.check_keyword(start, len, b"print", TokenType::Print),
+                        b'u' => self.check_keyword(start, len, b"pub", TokenType::Pub),
+                        _ => TokenType::Identifier,
+                    }
+                } else { TokenType::Identifier }
+            },
             b'r' => self.check_keyword(start, len, b"return", TokenType::Return),
             b's' => self.check_keyword(start, len, b"self", TokenType::SelfKw),
-            b't' => self.check_keyword(start, len, b"true", TokenType::True),
+            b't' => {
+                if len == 3 {
+                    self.check_keyword(start, len, b"try", TokenType::Try)
+                } else {
+                    self.check_keyword(start, len, b"true", TokenType::True)
+                }
+            },
+            b'u' => self.check_keyword(start, len, b"use", TokenType::Use),
             b'w' => self.check_keyword(start, len, b"while", TokenType::While),
             b'I' => self.check_keyword(start, len, b"Item", TokenType::Item),
+            b'O' => self.check_keyword(start, len, b"Ok", TokenType::Ok),
+            b'E' => self.check_keyword(start, len, b"Err", TokenType::Err),
             b'f' => {
                 if len >= 2 {
                     let second = unsafe { *start.add(1) };
@@ -225,9 +356,9 @@ impl Lexer {
 
         let slice = unsafe { std::slice::from_raw_parts(start, len) };
         if slice == expected {
-            return tokentype;
+            tokentype
         } else {
-            return TokenType::Identifier;
+            TokenType::Identifier
         }
     }
 }
@@ -5,35 +5,65 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
+    ColonColon,
     Dot,
     Minus,
     Plus,
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Pipe,
+    Question,
 
     // One or two character tokens
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    FatArrow,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PercentEqual,
+    DotDot,
+    DotDotEqual,
 
     // Literals
     Identifier,
     String,
+    /// The part of an interpolated string up to a `{`: `"count: {` or `} and {`.
+    Interpolation,
     Number,
 
     // Keywords
     And,
+    Assert,
+    AssertEq,
+    Panic,
+    Break,
+    Continue,
+    Try,
+    Catch,
+    Ok,
+    Err,
     Else,
     False,
     For,
     If,
+    In,
+    Match,
+    Mut,
+    Use,
     Nil,
     Or,
     Return,
@@ -44,6 +74,8 @@ pub enum TokenType {
     Form,
     Fn,
     Item,
+    Print,
+    Pub,
 
     // Special
     Error,
@@ -4,43 +4,140 @@ mod opcode;
 mod lexer;
 mod compiler;
 mod hash;
+mod optimizer;
 
 use crate::vm::InterpretResult;
 use crate::vm::VM;
 use std::fs;
-use crate::lexer::Lexer;
-use crate::compiler::Compiler;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use crate::chunk::Chunk;
+use crate::optimizer::OptLevel;
 
+/// Exit codes from sysexits.h, as clox uses them; `throw_error` exits with 64 (usage).
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+
+/// reef [-O0|-O1] [--trace[=FROM-TO]] file.rf
+/// reef build [-O0|-O1] file.rf [-o file.rfc]
+/// reef run [--trace[=FROM-TO]] file.rf|file.rfc
+/// reef disasm file.rf|file.rfc
 fn main() {
     let args:Vec<String> = std::env::args().collect();
 
+    let mut opt_level = OptLevel::O1;
+    let mut output: Option<&String> = None;
+    let mut trace: Option<RangeInclusive<u32>> = None;
+    let mut positional: Vec<&str> = Vec::new();
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-O0" => opt_level = OptLevel::O0,
+            "-O1" => opt_level = OptLevel::O1,
+            "-o" => match iter.next() {
+                Some(path) => output = Some(path),
+                None => throw_error("-o needs an output path"),
+            },
+            "--trace" => trace = Some(0..=u32::MAX),
+            _ if arg.starts_with("--trace=") => trace = Some(parse_line_range(&arg["--trace=".len()..])),
+            _ => positional.push(arg),
+        }
+    }
 
-    match args.len() {
-        1 => {},
-        2 => {
-            if args[1].split('.').last().map(|ext| ext.to_lowercase()) != Some("rf".to_string()) {
-                throw_error("The file must be .rf");
-            } else {
-                let bytes = fs::read(&args[1]).expect("Failed to read file");
-                let lexer = Lexer::new(bytes.as_slice());
-                let compiler = Compiler::new(lexer);
-                if let Some(chunk) = compiler.compile() {
-                    let mut vm = VM::new(chunk);
-                    match vm.run() {
-                        InterpretResult::RuntimeError{message, line} => eprintln!("[line: {}] Runtime Error.. {}...", line, message),
-                        _ => {},
-                    }
-                } else {}
-            }
-        },
+    match positional.as_slice() {
+        [] => {},
+        ["build", path] => build_file(path, output, opt_level),
+        ["run", path] | [path] => run_file(path, opt_level, trace),
+        ["disasm", path] => disasm_file(path, opt_level),
         _ => {
             throw_error("Too may arguments!");
         }
     }
 }
 
-fn throw_error(message: &str) {
+fn build_file(path: &str, output: Option<&String>, opt_level: OptLevel) {
+    if extension(path).as_deref() != Some("rf") {
+        throw_error("The file must be .rf");
+    }
+
+    let chunk = compile_file(path, opt_level);
+    let output = output.cloned().unwrap_or_else(|| format!("{}c", path));
+    let bytes = chunk.serialize();
+    unsafe { chunk.free_constants(); }
+    if let Err(error) = fs::write(&output, bytes) {
+        throw_error(&format!("Failed to write {}: {}", output, error));
+    }
+}
+
+fn run_file(path: &str, opt_level: OptLevel, trace: Option<RangeInclusive<u32>>) {
+    let mut vm = match VM::new(load_chunk(path, opt_level)) {
+        Ok(vm) => vm,
+        Err(error) => throw_error(&format!("Invalid bytecode in {}: {}", path, error)),
+    };
+    vm.set_trace(trace);
+    vm.set_opt_level(opt_level);
+    vm.set_script_dir(Path::new(path).parent().unwrap_or(Path::new("")));
+    match vm.run() {
+        // Whoever reads the output stopped reading, as `reef file.rf | head` does; that isn't a failure.
+        InterpretResult::Done | InterpretResult::OutputClosed => {},
+        InterpretResult::CompileError => std::process::exit(EXIT_COMPILE_ERROR),
+        InterpretResult::RuntimeError{error, line, stack_trace} => {
+            eprintln!("[line: {}] Runtime Error.. {}...", line, error);
+            for frame in stack_trace {
+                eprintln!("  at {} (line {})", frame.function, frame.line);
+            }
+            std::process::exit(EXIT_RUNTIME_ERROR);
+        },
+    }
+}
+
+fn disasm_file(path: &str, opt_level: OptLevel) {
+    let chunk = load_chunk(path, opt_level);
+    let mut out = std::io::stdout().lock();
+    let result = chunk.disassemble_all("<script>", &mut out);
+    unsafe { chunk.free_constants(); }
+    if let Err(error) = result {
+        throw_error(&format!("Failed to write disassembly: {}", error));
+    }
+}
+
+/// Compiles `.rf` sources and loads `.rfc` bytecode.
+fn load_chunk(path: &str, opt_level: OptLevel) -> Chunk {
+    match extension(path).as_deref() {
+        Some("rf") => compile_file(path, opt_level),
+        Some("rfc") => {
+            let bytes = fs::read(path).expect("Failed to read file");
+            match Chunk::deserialize(&bytes) {
+                Ok(chunk) => chunk,
+                Err(error) => throw_error(&format!("Invalid bytecode file {}: {}", path, error)),
+            }
+        },
+        _ => throw_error("The file must be .rf or .rfc"),
+    }
+}
+
+/// The compiler has already reported the errors when it gives up.
+fn compile_file(path: &str, opt_level: OptLevel) -> Chunk {
+    match compiler::compile_file(Path::new(path), opt_level).expect("Failed to read file") {
+        Some(chunk) => chunk,
+        None => std::process::exit(EXIT_COMPILE_ERROR),
+    }
+}
+
+/// `10-20` traces lines 10 through 20, `10` traces line 10 only.
+fn parse_line_range(spec: &str) -> RangeInclusive<u32> {
+    let (from, to) = spec.split_once('-').unwrap_or((spec, spec));
+    match (from.trim().parse::<u32>(), to.trim().parse::<u32>()) {
+        (Ok(from), Ok(to)) if from <= to => from..=to,
+        _ => throw_error("--trace expects a line range like 10-20"),
+    }
+}
+
+fn extension(path: &str) -> Option<String> {
+    path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase())
+}
+
+fn throw_error(message: &str) -> ! {
     eprintln!("{}", message);
     std::process::exit(64);
 }
-
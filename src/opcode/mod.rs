@@ -14,3 +14,43 @@ pub const OP_GREATER: u8 = 0x0D;
 pub const OP_LESS: u8 = 0x0E;
 pub const OP_POP: u8 = 0x0F;
 pub const OP_DEFINE_GLOBAL: u8 = 0x10;
+pub const OP_NOT: u8 = 0x11;
+pub const OP_NOT_EQUAL: u8 = 0x12;
+pub const OP_GREATER_EQUAL: u8 = 0x13;
+pub const OP_LESS_EQUAL: u8 = 0x14;
+pub const OP_GET_GLOBAL: u8 = 0x15;
+pub const OP_SET_GLOBAL: u8 = 0x16;
+pub const OP_GET_LOCAL: u8 = 0x17;
+pub const OP_SET_LOCAL: u8 = 0x18;
+pub const OP_MODULO: u8 = 0x19;
+pub const OP_DUP: u8 = 0x1A;
+pub const OP_CALL: u8 = 0x1B;
+pub const OP_BUILD_STRING: u8 = 0x1C;
+pub const OP_PRINT: u8 = 0x1D;
+pub const OP_BUILD_LIST: u8 = 0x1E;
+pub const OP_GET_INDEX: u8 = 0x1F;
+pub const OP_SET_INDEX: u8 = 0x20;
+pub const OP_DUP2: u8 = 0x21;
+pub const OP_INVOKE: u8 = 0x22;
+pub const OP_BUILD_MAP: u8 = 0x23;
+pub const OP_JUMP: u8 = 0x24;
+pub const OP_JUMP_IF_FALSE: u8 = 0x25;
+pub const OP_LOOP: u8 = 0x26;
+pub const OP_RANGE: u8 = 0x27;
+pub const OP_RANGE_INCLUSIVE: u8 = 0x28;
+pub const OP_ITER: u8 = 0x29;
+pub const OP_FOR_ITER: u8 = 0x2A;
+pub const OP_NO_MATCH: u8 = 0x2B;
+pub const OP_MATCH_RANGE: u8 = 0x2C;
+pub const OP_DEFINE_GLOBAL_MUT: u8 = 0x2D;
+pub const OP_IMPORT: u8 = 0x2E;
+pub const OP_GET_PROPERTY: u8 = 0x2F;
+pub const OP_EXPORT: u8 = 0x30;
+pub const OP_TRY: u8 = 0x31;
+pub const OP_END_TRY: u8 = 0x32;
+pub const OP_PROPAGATE: u8 = 0x33;
+pub const OP_OK: u8 = 0x34;
+pub const OP_ERR: u8 = 0x35;
+pub const OP_ASSERT: u8 = 0x36;
+pub const OP_ASSERT_EQ: u8 = 0x37;
+pub const OP_PANIC: u8 = 0x38;
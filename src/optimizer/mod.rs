@@ -0,0 +1,234 @@
+use crate::chunk::{Chunk, Values, Obj, ObjType, ObjString, u24_to_u32};
+use crate::compiler::make_obj_str;
+use crate::opcode::*;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    O0,
+    O1,
+}
+
+enum Op {
+    Constant(Values),
+    Simple(u8),
+    Byte(u8, u8),
+    /// A jump and the original offset it lands on.
+    Jump(u8, usize),
+}
+
+/// `offset` is where the instruction started in the original chunk; a folded
+/// instruction takes the offset of the first instruction it replaces.
+struct Instruction {
+    op: Op,
+    line: u32,
+    offset: usize,
+}
+
+/// Folds constant expressions and rebuilds the constant pool without entries that folding
+/// made unreachable, or returns the chunk unchanged if a jump would no longer fit. `- -x` is left alone unless `x` is a constant, since negating twice is
+/// still an error for anything but a number.
+/// Jump targets split the chunk into straight-line blocks, and nothing is folded across one:
+/// an instruction is only combined with earlier ones at or after `block_start`, and the
+/// instruction at `block_start` (possibly a jump target) is only ever replaced in place.
+pub fn optimize(chunk: Chunk) -> Chunk {
+    let (instructions, targets) = decode(&chunk);
+    let mut folded: Vec<Instruction> = Vec::with_capacity(chunk.code.len());
+    let mut created: Vec<*mut Obj> = Vec::new();
+    let mut block_start = 0;
+
+    for instruction in instructions {
+        if targets.contains(&instruction.offset) {
+            block_start = folded.len();
+        }
+        let fold = match instruction.op {
+            Op::Simple(opcode) => try_fold(&folded[block_start..], opcode),
+            Op::Byte(OP_BUILD_STRING, parts) => try_join(&folded[block_start..], parts as usize),
+            _ => None,
+        };
+        if let Some((value, arity)) = fold {
+            if let Values::Obj(obj) = value { created.push(obj); }
+            let Instruction { line, offset, .. } = folded[folded.len() - arity];
+            folded.truncate(folded.len() - arity);
+            folded.push(Instruction { op: Op::Constant(value), line, offset });
+            continue;
+        }
+        folded.push(instruction);
+    }
+
+    let Some(optimized) = encode(folded, chunk.code.len()) else {
+        // Renumbering the pool can widen constant loads; the compiler already checked the original jumps.
+        for obj in created {
+            unsafe { Obj::free(obj); }
+        }
+        return chunk;
+    };
+
+    // Objects that no longer appear in the pool were folded away or merged into an equal constant.
+    let live: HashSet<*mut Obj> = optimized.constants.iter()
+        .filter_map(|constant| match constant { Values::Obj(obj) => Some(*obj), _ => None })
+        .collect();
+    let mut freed: HashSet<*mut Obj> = HashSet::new();
+    let stale = chunk.constants.iter()
+        .filter_map(|constant| match constant { Values::Obj(obj) => Some(*obj), _ => None })
+        .chain(created);
+    for obj in stale {
+        if !live.contains(&obj) && freed.insert(obj) {
+            unsafe { Obj::free(obj); }
+        }
+    }
+
+    optimized
+}
+
+/// Also returns the offsets that some jump lands on.
+fn decode(chunk: &Chunk) -> (Vec<Instruction>, HashSet<usize>) {
+    let mut instructions = Vec::with_capacity(chunk.code.len());
+    let mut targets = HashSet::new();
+    let mut offset = 0;
+
+    while offset < chunk.code.len() {
+        let opcode = chunk.code[offset];
+        let line = chunk.get_line(offset);
+
+        let (op, size) = match opcode {
+            OP_CONSTANT => (Op::Constant(chunk.constants[chunk.code[offset + 1] as usize]), 2),
+            OP_CONSTANT_LONG => {
+                let index = u24_to_u32([
+                    chunk.code[offset + 1],
+                    chunk.code[offset + 2],
+                    chunk.code[offset + 3],
+                ]) as usize;
+                (Op::Constant(chunk.constants[index]), 4)
+            },
+            OP_TRUE => (Op::Constant(Values::Bool(true)), 1),
+            OP_FALSE => (Op::Constant(Values::Bool(false)), 1),
+            OP_NIL => (Op::Constant(Values::Nil), 1),
+            OP_GET_LOCAL | OP_SET_LOCAL | OP_CALL | OP_BUILD_STRING | OP_BUILD_LIST | OP_BUILD_MAP | OP_INVOKE | OP_MATCH_RANGE | OP_ASSERT | OP_PANIC => (Op::Byte(opcode, chunk.code[offset + 1]), 2),
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP | OP_FOR_ITER | OP_TRY => {
+                let target = chunk.jump_target(offset).expect("the compiler emits complete jumps");
+                targets.insert(target);
+                (Op::Jump(opcode, target), 3)
+            },
+            _ => (Op::Simple(opcode), 1),
+        };
+
+        instructions.push(Instruction { op, line, offset });
+        offset += size;
+    }
+
+    (instructions, targets)
+}
+
+/// Jumps are written with a placeholder distance and patched once every target's new offset is known.
+/// Returns `None` if a jump ends up too long for its 16-bit distance.
+fn encode(instructions: Vec<Instruction>, capacity: usize) -> Option<Chunk> {
+    let mut chunk = Chunk::new(capacity);
+    let mut new_offsets: HashMap<usize, usize> = HashMap::new();
+    let mut jumps: Vec<(usize, usize)> = Vec::new();
+
+    for Instruction { op, line, offset } in instructions {
+        new_offsets.insert(offset, chunk.code.len());
+        match op {
+            Op::Constant(Values::Bool(true)) => chunk.write_byte(OP_TRUE, line),
+            Op::Constant(Values::Bool(false)) => chunk.write_byte(OP_FALSE, line),
+            Op::Constant(Values::Nil) => chunk.write_byte(OP_NIL, line),
+            Op::Constant(value) => chunk.write_constant(value, line),
+            Op::Simple(opcode) => chunk.write_byte(opcode, line),
+            Op::Byte(opcode, operand) => {
+                chunk.write_byte(opcode, line);
+                chunk.write_byte(operand, line);
+            },
+            Op::Jump(opcode, target) => {
+                jumps.push((chunk.code.len(), target));
+                chunk.write_byte(opcode, line);
+                chunk.write_byte(0xff, line);
+                chunk.write_byte(0xff, line);
+            },
+        }
+    }
+
+    for (at, target) in jumps {
+        let target = new_offsets[&target];
+        let distance = if chunk.code[at] == OP_LOOP { at + 3 - target } else { target - (at + 3) };
+        let distance = u16::try_from(distance).ok()?;
+        chunk.code[at + 1..at + 3].copy_from_slice(&distance.to_be_bytes());
+    }
+
+    Some(chunk)
+}
+
+/// `OP_BUILD_STRING` over string constants only, like `"a" + "b" + "c"`, becomes one constant.
+fn try_join(folded: &[Instruction], parts: usize) -> Option<(Values, usize)> {
+    if parts == 0 { return None; }
+    let start = folded.len().checked_sub(parts)?;
+    let mut joined = String::new();
+    for instruction in &folded[start..] {
+        match instruction.op {
+            Op::Constant(value @ Values::Obj(obj)) if value.is_string() => joined.push_str(unsafe { ObjString::as_str(obj as *mut ObjString) }),
+            _ => return None,
+        }
+    }
+    Some((Values::Obj(unsafe { make_obj_str(joined.as_ptr(), joined.len()) } as *mut Obj), parts))
+}
+
+/// Returns the folded value and how many constant loads it replaces.
+/// Anything the VM would reject at runtime is left alone so the error still happens.
+fn try_fold(folded: &[Instruction], opcode: u8) -> Option<(Values, usize)> {
+    let constant = |back: usize| -> Option<Values> {
+        match &folded.get(folded.len().checked_sub(back)?)?.op {
+            Op::Constant(value) => Some(*value),
+            _ => None,
+        }
+    };
+
+    match opcode {
+        OP_NEGATE => match constant(1)? {
+            Values::Number(n) => Some((Values::Number(-n), 1)),
+            _ => None,
+        },
+        OP_NOT => Some((Values::Bool(constant(1)?.is_falsey()), 1)),
+        _ => fold_binary(opcode, constant(2)?, constant(1)?).map(|value| (value, 2)),
+    }
+}
+
+fn fold_binary(opcode: u8, a: Values, b: Values) -> Option<Values> {
+    match opcode {
+        OP_EQUAL => return Some(Values::Bool(a.equals(b))),
+        OP_NOT_EQUAL => return Some(Values::Bool(!a.equals(b))),
+        _ => {},
+    }
+
+    match (a, b) {
+        (Values::Number(a), Values::Number(b)) => match opcode {
+            OP_ADD => Some(Values::Number(a + b)),
+            OP_SUBTRACT => Some(Values::Number(a - b)),
+            OP_MULTIPLY => Some(Values::Number(a * b)),
+            OP_DIVIDE => Some(Values::Number(a / b)),
+            OP_MODULO => Some(Values::Number(a % b)),
+            OP_GREATER => Some(Values::Bool(a > b)),
+            OP_LESS => Some(Values::Bool(a < b)),
+            OP_GREATER_EQUAL => Some(Values::Bool(a >= b)),
+            OP_LESS_EQUAL => Some(Values::Bool(a <= b)),
+            _ => None,
+        },
+        (Values::Obj(a), Values::Obj(b)) => unsafe {
+            if !matches!(((*a).type_obj, (*b).type_obj), (ObjType::String, ObjType::String)) {
+                return None;
+            }
+            let (a, b) = (a as *mut ObjString, b as *mut ObjString);
+            match opcode {
+                OP_ADD => {
+                    let joined = [ObjString::as_str(a), ObjString::as_str(b)].concat();
+                    Some(Values::Obj(make_obj_str(joined.as_ptr(), joined.len()) as *mut Obj))
+                },
+                OP_GREATER => Some(Values::Bool(ObjString::as_str(a) > ObjString::as_str(b))),
+                OP_LESS => Some(Values::Bool(ObjString::as_str(a) < ObjString::as_str(b))),
+                OP_GREATER_EQUAL => Some(Values::Bool(ObjString::as_str(a) >= ObjString::as_str(b))),
+                OP_LESS_EQUAL => Some(Values::Bool(ObjString::as_str(a) <= ObjString::as_str(b))),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
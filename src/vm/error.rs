@@ -0,0 +1,96 @@
+use std::{fmt, io};
+
+/// Everything that can stop a running chunk. Type names come from `Values::type_name`.
+#[derive(Debug, PartialEq)]
+pub enum RuntimeErrorKind {
+    /// A binary operator got operands it has no meaning for, e.g. `"a" - 1`.
+    TypeMismatch { operator: &'static str, left: &'static str, right: &'static str },
+    /// A unary operator got an operand it has no meaning for, e.g. `-"a"`.
+    InvalidOperand { operator: &'static str, operand: &'static str },
+    UndefinedVariable(String),
+    /// A global, method or property name or a module path operand isn't a string. Compiled code
+    /// never does this, but the verifier doesn't track value types, so hand-built bytecode can.
+    NotAName,
+    /// Assigning to a global declared with plain `let`, or to a function.
+    ImmutableVariable(String),
+    NotCallable(&'static str),
+    Arity { expected: u8, got: u8 },
+    StackOverflow,
+    /// `print` couldn't write to stdout for a reason other than the reader going away.
+    Output(io::ErrorKind),
+    NotIndexable(&'static str),
+    IndexType { target: &'static str, index: &'static str },
+    /// The index is NaN, infinite or has a fractional part.
+    FractionalIndex(f64),
+    NegativeIndex(f64),
+    IndexOutOfRange { index: f64, len: usize },
+    InvalidSlice { start: usize, end: usize },
+    UndefinedMethod { receiver: &'static str, name: String },
+    PopEmpty,
+    /// NaN and objects other than strings can't be map keys.
+    UnhashableKey(&'static str),
+    MissingKey(String),
+    NotIterable(&'static str),
+    /// `OP_FOR_ITER` found something other than the iterator `OP_ITER` makes. Compiled loops
+    /// never do this, but the verifier doesn't track value types, so hand-built bytecode can.
+    NotAnIterator(&'static str),
+    /// No arm of a `match` accepted the value, shown as it would be written.
+    NoMatch(String),
+    /// `use` could not read or compile the module file.
+    ModuleLoad { path: String, reason: String },
+    /// The modules being loaded, from the one imported again back to itself.
+    ImportCycle(Vec<String>),
+    UndefinedItem { module: String, name: String },
+    /// The module defines the item but didn't declare it `pub`.
+    PrivateItem { module: String, name: String },
+    NoProperties(&'static str),
+    /// `?` met an `Err` at the top level of a file, outside any `try`; holds the `Err` as written.
+    UncaughtErr(String),
+    UnwrapErr(String),
+    /// `assert` got a falsey condition; holds its message, if it had one.
+    AssertFailed(Option<String>),
+    /// The two sides of a failed `assert_eq`, shown as they would be written.
+    AssertEqFailed { left: String, right: String },
+    Panic(Option<String>),
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::TypeMismatch { operator, left, right } => write!(f, "cannot apply '{}' to {} and {}", operator, left, right),
+            RuntimeErrorKind::InvalidOperand { operator, operand } => write!(f, "cannot apply '{}' to {}", operator, operand),
+            RuntimeErrorKind::UndefinedVariable(name) => write!(f, "undefined variable '{}'", name),
+            RuntimeErrorKind::NotAName => write!(f, "names and module paths must be strings"),
+            RuntimeErrorKind::ImmutableVariable(name) => write!(f, "cannot assign to immutable variable '{}'", name),
+            RuntimeErrorKind::NotCallable(callee) => write!(f, "can only call functions, not {}", callee),
+            RuntimeErrorKind::Arity { expected, got } => write!(f, "expected {} arguments but got {}", expected, got),
+            RuntimeErrorKind::StackOverflow => write!(f, "stack overflow"),
+            RuntimeErrorKind::Output(kind) => write!(f, "cannot write output: {}", kind),
+            RuntimeErrorKind::NotIndexable(target) => write!(f, "cannot index into {}", target),
+            RuntimeErrorKind::IndexType { target, index } => write!(f, "cannot index {} with {}", target, index),
+            RuntimeErrorKind::FractionalIndex(index) => write!(f, "index {} is not a whole number", index),
+            RuntimeErrorKind::NegativeIndex(index) => write!(f, "negative index {}", index),
+            RuntimeErrorKind::IndexOutOfRange { index, len } => write!(f, "index {} out of range for length {}", index, len),
+            RuntimeErrorKind::InvalidSlice { start, end } => write!(f, "slice start {} is after its end {}", start, end),
+            RuntimeErrorKind::UndefinedMethod { receiver, name } => write!(f, "{} has no method '{}'", receiver, name),
+            RuntimeErrorKind::PopEmpty => write!(f, "cannot pop from an empty list"),
+            RuntimeErrorKind::UnhashableKey(key) => write!(f, "{} cannot be used as a map key", key),
+            RuntimeErrorKind::MissingKey(key) => write!(f, "key {} not found in map", key),
+            RuntimeErrorKind::NotIterable(value) => write!(f, "cannot iterate over {}", value),
+            RuntimeErrorKind::NotAnIterator(value) => write!(f, "expected a loop iterator, found {}", value),
+            RuntimeErrorKind::NoMatch(value) => write!(f, "no match arm accepts {}", value),
+            RuntimeErrorKind::ModuleLoad { path, reason } => write!(f, "cannot load module {}: {}", path, reason),
+            RuntimeErrorKind::ImportCycle(modules) => write!(f, "import cycle: {}", modules.join(" -> ")),
+            RuntimeErrorKind::UndefinedItem { module, name } => write!(f, "module '{}' has no item '{}'", module, name),
+            RuntimeErrorKind::PrivateItem { module, name } => write!(f, "'{}' is private to module '{}'", name, module),
+            RuntimeErrorKind::NoProperties(receiver) => write!(f, "{} has no properties", receiver),
+            RuntimeErrorKind::UncaughtErr(error) => write!(f, "uncaught {}", error),
+            RuntimeErrorKind::UnwrapErr(error) => write!(f, "called unwrap on {}", error),
+            RuntimeErrorKind::AssertFailed(None) => write!(f, "assertion failed"),
+            RuntimeErrorKind::AssertFailed(Some(message)) => write!(f, "assertion failed: {}", message),
+            RuntimeErrorKind::AssertEqFailed { left, right } => write!(f, "assertion failed: left is {}, right is {}", left, right),
+            RuntimeErrorKind::Panic(None) => write!(f, "panicked"),
+            RuntimeErrorKind::Panic(Some(message)) => write!(f, "panicked: {}", message),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use super::VM;
+use super::error::RuntimeErrorKind;
+use crate::chunk::{Values, Obj, ObjType, ObjString, ObjList, ObjMap, ObjRange, ObjIterator, IterState};
+use crate::compiler;
+
+impl VM {
+    /// `OP_ITER`: lists yield their items, maps their keys, strings their characters
+    /// and ranges their numbers, counting up by one until the next number would be the same.
+    pub(super) fn make_iterator(&mut self, iterable: Values) -> Result<Values, RuntimeErrorKind> {
+        let Values::Obj(obj) = iterable else {
+            return Err(RuntimeErrorKind::NotIterable(iterable.type_name()));
+        };
+        let state = unsafe {
+            match (*obj).type_obj {
+                ObjType::List => IterState::List { list: obj as *mut ObjList, index: 0 },
+                ObjType::Map => {
+                    let keys = (*(obj as *mut ObjMap)).table.iter().map(|(key, _)| key).collect();
+                    IterState::Keys { keys, index: 0 }
+                },
+                ObjType::String => IterState::Chars { string: obj as *mut ObjString, offset: 0 },
+                ObjType::Range => {
+                    let range = &*(obj as *mut ObjRange);
+                    IterState::Range { start: range.start, count: 0, end: range.end, inclusive: range.inclusive }
+                },
+                _ => return Err(RuntimeErrorKind::NotIterable(iterable.type_name())),
+            }
+        };
+
+        let iterator = compiler::make_obj_iterator(state) as *mut Obj;
+        unsafe { self.link(iterator); }
+        Ok(Values::Obj(iterator))
+    }
+
+    /// `OP_FOR_ITER`: the next item, or `None` once the iterator is exhausted.
+    pub(super) fn iterate(&mut self, iterator: *mut ObjIterator) -> Option<Values> {
+        match unsafe { &mut (*iterator).state } {
+            IterState::List { list, index } => {
+                let items = unsafe { &(**list).items };
+                let item = items.get(*index).copied()?;
+                *index += 1;
+                Some(item)
+            },
+            IterState::Keys { keys, index } => {
+                let key = keys.get(*index).copied()?;
+                *index += 1;
+                Some(key)
+            },
+            IterState::Chars { string, offset } => {
+                let rest = unsafe { &ObjString::as_str(*string)[*offset..] };
+                let len = rest.chars().next()?.len_utf8();
+                let char = unsafe { compiler::make_obj_str(rest.as_ptr(), len) } as *mut Obj;
+                *offset += len;
+                unsafe { self.link(char); }
+                Some(Values::Obj(char))
+            },
+            IterState::Range { start, count, end, inclusive } => {
+                let item = *start + *count as f64;
+                // Past 2^53 (or from an infinite start) adding one no longer changes the number,
+                // so the range stops instead of yielding the same value forever.
+                if *count > 0 && item == *start + (*count - 1) as f64 { return None; }
+                let more = if *inclusive { item <= *end } else { item < *end };
+                if !more { return None; }
+                *count += 1;
+                Some(Values::Number(item))
+            },
+        }
+    }
+
+    /// `OP_RANGE` and `OP_RANGE_INCLUSIVE`. Both ends must be numbers.
+    pub(super) fn new_range(&mut self, start: Values, end: Values, inclusive: bool) -> Result<Values, RuntimeErrorKind> {
+        let (Values::Number(start), Values::Number(end)) = (start, end) else {
+            let operator = if inclusive { "..=" } else { ".." };
+            return Err(RuntimeErrorKind::TypeMismatch { operator, left: start.type_name(), right: end.type_name() });
+        };
+        let range = compiler::make_obj_range(start, end, inclusive) as *mut Obj;
+        unsafe { self.link(range); }
+        Ok(Values::Obj(range))
+    }
+}
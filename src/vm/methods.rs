@@ -0,0 +1,253 @@
+use super::VM;
+use super::error::RuntimeErrorKind;
+use super::table::{self, Table};
+use crate::chunk::{Values, Obj, ObjType, ObjString, ObjList, ObjMap, ObjResult};
+use crate::compiler;
+
+impl VM {
+    /// Runs the built-in method named on the stack. The layout is
+    /// `receiver name arg...`, and everything from the receiver up is replaced by the result.
+    pub(super) fn invoke(&mut self, arg_count: u8) -> Result<(), RuntimeErrorKind> {
+        let arg_count = arg_count as usize;
+        let base = self.stack.len() - arg_count - 2;
+        let receiver = self.stack[base];
+        let Some(name_obj) = super::name_string(self.stack[base + 1]) else { return Err(RuntimeErrorKind::NotAName) };
+        let name = unsafe { ObjString::as_str(name_obj) };
+
+        // A module item is an ordinary call once the item replaces the module and the name is gone.
+        if let Values::Obj(obj) = receiver && unsafe { matches!((*obj).type_obj, ObjType::Module) } {
+            let item = self.get_property(receiver, name_obj)?;
+            self.stack.remove(base + 1);
+            self.stack[base] = item;
+            return self.call_value(item, arg_count as u8);
+        }
+
+        let result = match receiver {
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::List) } => {
+                self.list_method(obj as *mut ObjList, name, base + 2)?
+            },
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Map) } => {
+                self.map_method(obj as *mut ObjMap, name, base + 2)?
+            },
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Result) } => {
+                self.result_method(obj as *mut ObjResult, name, base + 2)?
+            },
+            _ => return Err(RuntimeErrorKind::UndefinedMethod { receiver: receiver.type_name(), name: name.to_string() }),
+        };
+
+        self.stack.truncate(base);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Arguments start at stack index `args`.
+    fn list_method(&mut self, list: *mut ObjList, name: &str, args: usize) -> Result<Values, RuntimeErrorKind> {
+        let arg_count = self.stack.len() - args;
+        let expect = |expected: u8| {
+            if arg_count == expected as usize { Ok(()) } else { Err(RuntimeErrorKind::Arity { expected, got: arg_count as u8 }) }
+        };
+        let items = unsafe { &mut (*list).items };
+
+        match name {
+            "push" => {
+                expect(1)?;
+                items.push(self.stack[args]);
+                Ok(Values::Nil)
+            },
+            "pop" => {
+                expect(0)?;
+                items.pop().ok_or(RuntimeErrorKind::PopEmpty)
+            },
+            "len" => {
+                expect(0)?;
+                Ok(Values::Number(items.len() as f64))
+            },
+            "insert" => {
+                expect(2)?;
+                let index = list_index(self.stack[args], items.len(), true)?;
+                items.insert(index, self.stack[args + 1]);
+                Ok(Values::Nil)
+            },
+            "remove" => {
+                expect(1)?;
+                let index = list_index(self.stack[args], items.len(), false)?;
+                Ok(items.remove(index))
+            },
+            "contains" => {
+                expect(1)?;
+                let needle = self.stack[args];
+                Ok(Values::Bool(items.iter().any(|item| item.equals(needle))))
+            },
+            "slice" => {
+                expect(2)?;
+                let start = list_index(self.stack[args], items.len(), true)?;
+                let end = list_index(self.stack[args + 1], items.len(), true)?;
+                if start > end {
+                    return Err(RuntimeErrorKind::InvalidSlice { start, end });
+                }
+                let slice = items[start..end].to_vec();
+                Ok(self.new_list(slice))
+            },
+            _ => Err(RuntimeErrorKind::UndefinedMethod { receiver: "list", name: name.to_string() }),
+        }
+    }
+
+    /// Arguments start at stack index `args`.
+    fn map_method(&mut self, map: *mut ObjMap, name: &str, args: usize) -> Result<Values, RuntimeErrorKind> {
+        let arg_count = self.stack.len() - args;
+        let expect = |expected: u8| {
+            if arg_count == expected as usize { Ok(()) } else { Err(RuntimeErrorKind::Arity { expected, got: arg_count as u8 }) }
+        };
+        let table = unsafe { &mut (*map).table };
+
+        match name {
+            "keys" => {
+                expect(0)?;
+                let keys = table.iter().map(|(key, _)| key).collect();
+                Ok(self.new_list(keys))
+            },
+            "values" => {
+                expect(0)?;
+                let values = table.iter().map(|(_, value)| value).collect();
+                Ok(self.new_list(values))
+            },
+            "len" => {
+                expect(0)?;
+                Ok(Values::Number(table.len() as f64))
+            },
+            "has" => {
+                expect(1)?;
+                let key = self.stack[args];
+                check_key(key)?;
+                Ok(Values::Bool(table.table_get(key).is_some()))
+            },
+            "remove" => {
+                expect(1)?;
+                let key = self.stack[args];
+                check_key(key)?;
+                let value = table.table_get(key).ok_or_else(|| missing_key(key))?;
+                table.table_delete(key);
+                Ok(value)
+            },
+            _ => Err(RuntimeErrorKind::UndefinedMethod { receiver: "map", name: name.to_string() }),
+        }
+    }
+
+    fn result_method(&mut self, result: *mut ObjResult, name: &str, args: usize) -> Result<Values, RuntimeErrorKind> {
+        let arg_count = self.stack.len() - args;
+        let expect = |expected: u8| {
+            if arg_count == expected as usize { Ok(()) } else { Err(RuntimeErrorKind::Arity { expected, got: arg_count as u8 }) }
+        };
+        let ObjResult { ok, value, .. } = unsafe { &*result };
+
+        match name {
+            "is_ok" => {
+                expect(0)?;
+                Ok(Values::Bool(*ok))
+            },
+            "is_err" => {
+                expect(0)?;
+                Ok(Values::Bool(!*ok))
+            },
+            "unwrap" => {
+                expect(0)?;
+                if *ok { Ok(*value) } else { Err(RuntimeErrorKind::UnwrapErr(Values::Obj(result as *mut Obj).to_string())) }
+            },
+            "unwrap_or" => {
+                expect(1)?;
+                Ok(if *ok { *value } else { self.stack[args] })
+            },
+            _ => Err(RuntimeErrorKind::UndefinedMethod { receiver: "result", name: name.to_string() }),
+        }
+    }
+
+    pub(super) fn get_index(&mut self, target: Values, index: Values) -> Result<Values, RuntimeErrorKind> {
+        match target {
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::List) } => {
+                let items = unsafe { &(*(obj as *mut ObjList)).items };
+                Ok(items[list_index(index, items.len(), false)?])
+            },
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Map) } => {
+                check_key(index)?;
+                let table = unsafe { &(*(obj as *mut ObjMap)).table };
+                table.table_get(index).ok_or_else(|| missing_key(index))
+            },
+            _ => Err(RuntimeErrorKind::NotIndexable(target.type_name())),
+        }
+    }
+
+    pub(super) fn set_index(&mut self, target: Values, index: Values, value: Values) -> Result<(), RuntimeErrorKind> {
+        match target {
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::List) } => {
+                let items = unsafe { &mut (*(obj as *mut ObjList)).items };
+                let index = list_index(index, items.len(), false)?;
+                items[index] = value;
+                Ok(())
+            },
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Map) } => {
+                check_key(index)?;
+                unsafe { (*(obj as *mut ObjMap)).table.table_set(index, value); }
+                Ok(())
+            },
+            _ => Err(RuntimeErrorKind::NotIndexable(target.type_name())),
+        }
+    }
+
+    /// Allocates a list the VM owns from here on.
+    pub(super) fn new_list(&mut self, items: Vec<Values>) -> Values {
+        let list = compiler::make_obj_list(items) as *mut Obj;
+        unsafe { self.link(list); }
+        Values::Obj(list)
+    }
+
+    pub(super) fn new_result(&mut self, ok: bool, value: Values) -> Values {
+        let result = compiler::make_obj_result(ok, value) as *mut Obj;
+        unsafe { self.link(result); }
+        Values::Obj(result)
+    }
+
+    /// Allocates a map from `key value key value ...`; a repeated key keeps its last value.
+    pub(super) fn new_map(&mut self, pairs: &[Values]) -> Result<Values, RuntimeErrorKind> {
+        let mut table = Table::new();
+        for pair in pairs.chunks_exact(2) {
+            check_key(pair[0])?;
+            table.table_set(pair[0], pair[1]);
+        }
+        let map = compiler::make_obj_map(table) as *mut Obj;
+        unsafe { self.link(map); }
+        Ok(Values::Obj(map))
+    }
+}
+
+fn check_key(key: Values) -> Result<(), RuntimeErrorKind> {
+    match table::hash_key(key) {
+        Some(_) => Ok(()),
+        None if matches!(key, Values::Number(_)) => Err(RuntimeErrorKind::UnhashableKey("NaN")),
+        None => Err(RuntimeErrorKind::UnhashableKey(key.type_name())),
+    }
+}
+
+/// Strings are quoted so `m["1"]` and `m[1]` read differently in the message.
+fn missing_key(key: Values) -> RuntimeErrorKind {
+    if key.is_string() { RuntimeErrorKind::MissingKey(format!("\"{}\"", key)) } else { RuntimeErrorKind::MissingKey(key.to_string()) }
+}
+
+/// Lists only take whole, non-negative numbers below `len`; `allow_end` also accepts `len`
+/// itself, for positions between items (`insert`, `slice`).
+fn list_index(index: Values, len: usize, allow_end: bool) -> Result<usize, RuntimeErrorKind> {
+    let Values::Number(index) = index else {
+        return Err(RuntimeErrorKind::IndexType { target: "list", index: index.type_name() });
+    };
+    // NaN and the infinities have a NaN fractional part, so they are rejected here too.
+    if index.fract() != 0.0 {
+        return Err(RuntimeErrorKind::FractionalIndex(index));
+    }
+    if index < 0.0 {
+        return Err(RuntimeErrorKind::NegativeIndex(index));
+    }
+    let out_of_range = if allow_end { index > len as f64 } else { index >= len as f64 };
+    if out_of_range {
+        return Err(RuntimeErrorKind::IndexOutOfRange { index, len });
+    }
+    Ok(index as usize)
+}
@@ -1,62 +1,426 @@
 pub mod table;
+pub mod error;
+mod methods;
+mod iter;
+mod module;
+#[cfg(test)]
+mod tests;
 
+use table::Table;
+use error::RuntimeErrorKind;
 use super::{
-    chunk::{Chunk, Values, Obj, ObjType, ObjString},
+    chunk::{Chunk, Values, Obj, ObjType, ObjString, ObjFunction, ObjIterator, ObjModule, ObjResult, verify::{self, VerifyError}},
     opcode::*,
     compiler::{self},
+    optimizer::OptLevel,
 };
-use std::alloc::{self, Layout};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
 macro_rules! binary_op {
-    ($stack:expr, $op:tt, $line:expr) => {{
-        match (unsafe { $stack.pop().unwrap_unchecked() }, unsafe { $stack.pop().unwrap_unchecked() }) {
+    ($vm:expr, $op:tt) => {{
+        match (unsafe { $vm.stack.pop().unwrap_unchecked() }, unsafe { $vm.stack.pop().unwrap_unchecked() }) {
             (Values::Number(b), Values::Number(a)) => {
-                $stack.push(Values::Number(a $op b));
+                $vm.stack.push(Values::Number(a $op b));
             },
-            _ => return (InterpretResult::RuntimeError { message: String::from("failed to binary_op!"), line: $line, }),
+            (b, a) => return $vm.type_mismatch(stringify!($op), a, b),
         }
     }};
 }
 
+/// Strings order by their UTF-8 bytes, which is the same as code point order.
+macro_rules! compare_op {
+    ($vm:expr, $op:tt) => {{
+        match (unsafe { $vm.stack.pop().unwrap_unchecked() }, unsafe { $vm.stack.pop().unwrap_unchecked() }) {
+            (Values::Number(b), Values::Number(a)) => {
+                $vm.stack.push(Values::Bool(a $op b));
+            },
+            (Values::Obj(b), Values::Obj(a)) if unsafe { matches!(((*a).type_obj, (*b).type_obj), (ObjType::String, ObjType::String)) } => {
+                let (a, b) = unsafe { (ObjString::as_str(a as *mut ObjString), ObjString::as_str(b as *mut ObjString)) };
+                $vm.stack.push(Values::Bool(a $op b));
+            },
+            (b, a) => return $vm.type_mismatch(stringify!($op), a, b),
+        }
+    }};
+}
+
+/// Deep enough for ordinary recursion, shallow enough to fail long before the host stack would.
+pub const FRAMES_MAX: usize = 1024;
+
 pub enum InterpretResult {
     Done,
+    #[allow(dead_code)]
     CompileError,
+    /// `line` is where the error happened; `stack_trace` lists every active call, innermost first.
     RuntimeError {
-        message: String,
+        error: RuntimeErrorKind,
         line: u32,
+        stack_trace: Vec<TraceFrame>,
     },
+    /// stdout was closed under a `print`; the script stopped there.
+    OutputClosed,
+}
+
+pub struct TraceFrame {
+    pub function: String,
+    pub line: u32,
+}
+
+/// `ip` is only written back when another frame is pushed on top; the running frame's ip lives in `VM::ip`.
+/// `slots` is the stack index of slot 0, which holds the function being run.
+/// `module` is only set on the frame running a module's top-level code, which `OP_RETURN`
+/// marks as loaded and leaves on the stack in place of the return value.
+struct CallFrame {
+    function: *mut ObjFunction,
+    ip: *const u8,
+    slots: usize,
+    module: *mut ObjModule,
+}
+
+/// An active `try` block: errors unwind to `frames` frames and `stack_len` values,
+/// push the error and continue at `catch`.
+struct Handler {
+    frames: usize,
+    stack_len: usize,
+    catch: *const u8,
 }
 
 pub struct VM {
-    pub chunk: Chunk,
+    frames: Vec<CallFrame>,
+    /// Innermost last.
+    handlers: Vec<Handler>,
     ip: *const u8,
     stack: Vec<Values>,
+    globals: Table,
+    /// Names of the globals defined by `OP_DEFINE_GLOBAL` rather than `OP_DEFINE_GLOBAL_MUT`.
+    immutable_globals: Table,
+    /// Every module loaded by `use`, keyed by its resolved path.
+    modules: Table,
     objects: *mut Obj,
+    trace: Option<RangeInclusive<u32>>,
+    /// `print` writes here instead of stdout when set; tests use it to read the output.
+    captured: Option<Vec<u8>>,
+    /// How modules are compiled when `use` loads them.
+    opt_level: OptLevel,
+    /// What the script's `use` paths are relative to; a module's are relative to its own file.
+    script_dir: PathBuf,
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> Self {
-        let ip = chunk.code.as_ptr();
-        let stack_capacity = chunk.code.len();
-        Self {
-            chunk,
-            ip,
-            stack: Vec::with_capacity(stack_capacity * 2),
+    /// Only verified chunks are run, which is what makes the unchecked stack
+    /// and constant accesses in `run` sound.
+    pub fn new(chunk: Chunk) -> Result<Self, VerifyError> {
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            handlers: Vec::new(),
+            ip: std::ptr::null(),
+            stack: Vec::new(),
+            globals: Table::new(),
+            immutable_globals: Table::new(),
+            modules: Table::new(),
             objects: std::ptr::null_mut(),
+            trace: None,
+            captured: None,
+            opt_level: OptLevel::O1,
+            script_dir: PathBuf::new(),
+        };
+        vm.load(chunk)?;
+        Ok(vm)
+    }
+
+    /// Wraps `chunk` in a script function and makes it the next thing `run` executes.
+    /// Globals survive, so a VM can keep loading chunks after an earlier one failed.
+    pub fn load(&mut self, chunk: Chunk) -> Result<(), VerifyError> {
+        let script = compiler::make_obj_function(std::ptr::null_mut(), 0, chunk);
+        // The VM takes ownership of the script and of every object in its constant pools exactly once,
+        // no matter how many times a constant is loaded, so they are freed even if verification fails.
+        unsafe { self.link_function(script, std::ptr::null_mut()); }
+        unsafe { verify::verify_function(script)?; }
+
+        self.stack.clear();
+        self.stack.reserve(unsafe { (*script).max_stack });
+        self.stack.push(Values::Obj(script as *mut Obj));
+        self.frames.clear();
+        self.handlers.clear();
+        self.frames.push(CallFrame { function: script, ip: unsafe { (*script).chunk.code.as_ptr() }, slots: 0, module: std::ptr::null_mut() });
+        self.ip = unsafe { (*script).chunk.code.as_ptr() };
+        Ok(())
+    }
+
+    /// Also ties every function in the chunk to the globals of `module`.
+    unsafe fn link_function(&mut self, function: *mut ObjFunction, module: *mut ObjModule) {
+        unsafe {
+            self.link(function as *mut Obj);
+            (*function).module = module;
+            for constant in &(*function).chunk.constants {
+                if let Values::Obj(obj) = *constant {
+                    match (*obj).type_obj {
+                        ObjType::Function => self.link_function(obj as *mut ObjFunction, module),
+                        _ => self.link(obj),
+                    }
+                }
+            }
         }
     }
 
+    unsafe fn link(&mut self, obj: *mut Obj) {
+        unsafe { (*obj).next = self.objects; }
+        self.objects = obj;
+    }
+
+    /// Replaces the top `parts` values with their `to_string`s joined together.
+    /// Strings are copied straight into the result, which is sized up front and allocated once.
+    fn concatenate(&mut self, parts: usize) {
+        let start = self.stack.len() - parts;
+        let rendered: Vec<Option<String>> = self.stack[start..].iter()
+            .map(|part| (!part.is_string()).then(|| part.to_string()))
+            .collect();
+        let text = |i: usize| -> &[u8] {
+            match (&rendered[i], self.stack[start + i]) {
+                (Some(text), _) => text.as_bytes(),
+                (None, Values::Obj(obj)) => unsafe { ObjString::as_str(obj as *mut ObjString) }.as_bytes(),
+                (None, _) => unreachable!("only strings are left unrendered"),
+            }
+        };
+
+        let length = (0..parts).map(|i| text(i).len()).sum();
+        let string = unsafe { compiler::alloc_obj_str(length) };
+        let mut offset = 0;
+        for i in 0..parts {
+            let bytes = text(i);
+            unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), (*string).chars.add(offset), bytes.len()); }
+            offset += bytes.len();
+        }
+
+        self.stack.truncate(start);
+        unsafe { self.link(string as *mut Obj); }
+        self.stack.push(Values::Obj(string as *mut Obj));
+    }
+
+    /// Reads a jump distance and moves past it.
+    fn read_u16(&mut self) -> usize {
+        let distance = unsafe { u16::from_be_bytes([*self.ip, *self.ip.add(1)]) };
+        self.ip = unsafe { self.ip.add(2) };
+        distance as usize
+    }
+
+    fn frame(&self) -> &CallFrame {
+        unsafe { self.frames.last().unwrap_unchecked() }
+    }
+
+    fn chunk(&self) -> &Chunk {
+        unsafe { &(*self.frame().function).chunk }
+    }
+
+    /// The globals of the module the running function belongs to.
+    fn globals(&mut self) -> &mut Table {
+        let module = unsafe { (*self.frame().function).module };
+        if module.is_null() { &mut self.globals } else { unsafe { &mut (*module).globals } }
+    }
+
+    fn immutable_globals(&mut self) -> &mut Table {
+        let module = unsafe { (*self.frame().function).module };
+        if module.is_null() { &mut self.immutable_globals } else { unsafe { &mut (*module).immutable_globals } }
+    }
+
+    /// Inside a `try`, the error's message is caught like any other error (see `throw`).
+    /// Otherwise builds the stack trace, then unwinds every frame so the VM can be given another chunk.
+    fn runtime_error(&mut self, error: RuntimeErrorKind) -> InterpretResult {
+        if !self.handlers.is_empty() {
+            let message = error.to_string();
+            let string = unsafe { compiler::make_obj_str(message.as_ptr(), message.len()) } as *mut Obj;
+            unsafe { self.link(string); }
+            self.throw(Values::Obj(string));
+            // Frames are left to run, so `run` resumes in the catch block.
+            return InterpretResult::Done;
+        }
+
+        let top = self.frames.len() - 1;
+        let stack_trace: Vec<TraceFrame> = self.frames.iter().enumerate().rev()
+            .map(|(depth, frame)| {
+                let ip = if depth == top { self.ip } else { frame.ip };
+                unsafe {
+                    let chunk = &(*frame.function).chunk;
+                    // ip already points past the failing instruction (or the call), so step back into it.
+                    let offset = ip.offset_from(chunk.code.as_ptr()) as usize - 1;
+                    TraceFrame {
+                        function: ObjFunction::name(frame.function).to_string(),
+                        line: chunk.get_line(offset),
+                    }
+                }
+            })
+            .collect();
+
+        self.forget_unfinished_modules(0);
+        self.stack.clear();
+        self.frames.clear();
+        InterpretResult::RuntimeError { error, line: stack_trace[0].line, stack_trace }
+    }
+
+    /// Failed assertions and panics stop the script even inside a `try`, so a test can't swallow them.
+    fn abort(&mut self, error: RuntimeErrorKind) -> InterpretResult {
+        self.handlers.clear();
+        self.runtime_error(error)
+    }
+
+    /// Unwinds to the innermost `try` and continues in its catch block with `error` pushed.
+    /// Returns false, changing nothing, when no `try` is active.
+    fn throw(&mut self, error: Values) -> bool {
+        let Some(handler) = self.handlers.pop() else { return false; };
+        self.forget_unfinished_modules(handler.frames);
+        self.frames.truncate(handler.frames);
+        self.stack.truncate(handler.stack_len);
+        self.stack.push(error);
+        self.ip = handler.catch;
+        true
+    }
+
+    /// Modules whose top-level code is cut short by unwinding the frames from `first_frame` up
+    /// are forgotten, so a later `use` loads them afresh.
+    fn forget_unfinished_modules(&mut self, first_frame: usize) {
+        for frame in &self.frames[first_frame..] {
+            if !frame.module.is_null() {
+                self.modules.table_delete(Values::Obj(unsafe { (*frame.module).path } as *mut Obj));
+            }
+        }
+    }
+
+    /// Pops the running frame and hands `result` to its caller, or leaves the module on the
+    /// stack if the frame ran a module's top-level code. Returns true once the last frame is gone.
+    fn return_from_frame(&mut self, mut result: Values) -> bool {
+        let frame = unsafe { self.frames.pop().unwrap_unchecked() };
+        self.stack.truncate(frame.slots);
+        // A `try` never outlives the call it was entered in.
+        while self.handlers.last().is_some_and(|handler| handler.frames > self.frames.len()) {
+            self.handlers.pop();
+        }
+        if self.frames.is_empty() {
+            return true;
+        }
+        if !frame.module.is_null() {
+            unsafe { (*frame.module).loaded = true; }
+            result = Values::Obj(frame.module as *mut Obj);
+        }
+        self.stack.push(result);
+        self.ip = self.frame().ip;
+        false
+    }
+
+    /// Stops the script the way an error would, but with nothing to report.
+    fn output_closed(&mut self) -> InterpretResult {
+        self.stack.clear();
+        self.frames.clear();
+        InterpretResult::OutputClosed
+    }
+
+    fn undefined_variable(&mut self, name: *mut ObjString) -> InterpretResult {
+        let name = unsafe { ObjString::as_str(name) }.to_string();
+        self.runtime_error(RuntimeErrorKind::UndefinedVariable(name))
+    }
+
+    fn type_mismatch(&mut self, operator: &'static str, left: Values, right: Values) -> InterpretResult {
+        self.runtime_error(RuntimeErrorKind::TypeMismatch { operator, left: left.type_name(), right: right.type_name() })
+    }
+
+    /// Pushes a frame for `callee`, whose arguments are the top `arg_count` values on the stack.
+    fn call_value(&mut self, callee: Values, arg_count: u8) -> Result<(), RuntimeErrorKind> {
+        let function = match callee {
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Function) } => obj as *mut ObjFunction,
+            _ => return Err(RuntimeErrorKind::NotCallable(callee.type_name())),
+        };
+
+        let arity = unsafe { (*function).arity };
+        if arg_count != arity {
+            return Err(RuntimeErrorKind::Arity { expected: arity, got: arg_count });
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeErrorKind::StackOverflow);
+        }
+
+        self.push_frame(function, std::ptr::null_mut());
+        Ok(())
+    }
+
+    /// Starts running `function`, whose arguments are already on the stack above it.
+    fn push_frame(&mut self, function: *mut ObjFunction, module: *mut ObjModule) {
+        unsafe { self.frames.last_mut().unwrap_unchecked().ip = self.ip; }
+        self.stack.reserve(unsafe { (*function).max_stack });
+        let code = unsafe { (*function).chunk.code.as_ptr() };
+        self.frames.push(CallFrame {
+            function,
+            ip: code,
+            slots: self.stack.len() - unsafe { (*function).arity } as usize - 1,
+            module,
+        });
+        self.ip = code;
+    }
+
+    /// Modules `use`d by the running code are compiled with `opt_level`; the default is `-O1`.
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    /// The directory of the file the script came from; the default is the working directory.
+    pub fn set_script_dir(&mut self, dir: &Path) {
+        self.script_dir = dir.to_path_buf();
+    }
+
+    /// `Some(lines)` prints every instruction executed on those source lines,
+    /// together with the stack it sees, to stderr. `None` turns tracing off.
+    pub fn set_trace(&mut self, lines: Option<RangeInclusive<u32>>) {
+        self.trace = lines;
+    }
+
+    #[cfg(test)]
+    pub fn capture_output(&mut self) {
+        self.captured = Some(Vec::new());
+    }
+
+    #[cfg(test)]
+    pub fn output(&self) -> &str {
+        std::str::from_utf8(self.captured.as_deref().unwrap_or_default()).unwrap()
+    }
+
     pub fn run(&mut self) -> InterpretResult {
-        #[cfg(debug_assertions)]
-        {
-            println!("== test at VM(in process) ==");
+        // Nothing is loaded, or the last chunk already finished or failed.
+        if self.frames.is_empty() {
+            return InterpretResult::Done;
+        }
+
+        // Tracing picks a separately compiled loop, so the untraced one carries no checks for it.
+        // `execute` also returns after a caught error; with frames left, it resumes in the catch block.
+        loop {
+            let result = if self.trace.is_some() {
+                self.execute::<true>()
+            } else {
+                self.execute::<false>()
+            };
+            if self.frames.is_empty() {
+                return result;
+            }
+        }
+    }
+
+    fn trace_instruction(&self) {
+        let chunk = self.chunk();
+        let offset = unsafe { self.ip.offset_from(chunk.code.as_ptr()) } as usize;
+        let in_range = self.trace.as_ref().is_some_and(|lines| lines.contains(&chunk.get_line(offset)));
+        if !in_range { return; }
+
+        let mut err = std::io::stderr().lock();
+        let _ = write!(err, "          ");
+        for value in &self.stack {
+            let _ = write!(err, "[ {} ]", value);
         }
+        let _ = writeln!(err);
+        let _ = chunk.disassemble_instruction(offset, &mut err);
+    }
 
+    fn execute<const TRACE: bool>(&mut self) -> InterpretResult {
         loop {
-            #[cfg(debug_assertions)]
-            {
-                let offset = unsafe { self.ip.offset_from(self.chunk.code.as_ptr()) } as usize;
-                self.chunk.chunk_match(offset);
+            if TRACE {
+                self.trace_instruction();
             }
 
             let instruction = unsafe {
@@ -65,36 +429,26 @@ impl VM {
                 instruction
             };
 
-            let offset = unsafe { self.ip.offset_from(self.chunk.code.as_ptr()) } as usize;
-            let line = self.chunk.get_line(offset);
-            
             match instruction {
                 OP_RETURN => {
-                    #[cfg(debug_assertions)]
-                    {
-                        println!("== test at VM(ended - before poping last value) ==");
-                        for i in &self.stack {
-                            println!("{:?}", i);
-                        }
-                    }
-                    match self.stack.pop() {
-                        Some(_) => return InterpretResult::Done,
-                        _ => return InterpretResult::RuntimeError {
-                            message: String::from("Oops!  You couldn't make return from [Brr brr patapim!]..."),
-                            line,
-                        },
+                    let result = unsafe { self.stack.pop().unwrap_unchecked() };
+                    if self.return_from_frame(result) {
+                        return InterpretResult::Done;
+                    }
+                },
+                OP_CALL => {
+                    let arg_count = unsafe { *self.ip };
+                    self.ip = unsafe { self.ip.add(1) };
+                    let callee = unsafe { *self.stack.get_unchecked(self.stack.len() - 1 - arg_count as usize) };
+                    if let Err(error) = self.call_value(callee, arg_count) {
+                        return self.runtime_error(error);
                     }
                 },
                 OP_CONSTANT => {
                     let constant_idx = unsafe { *self.ip } as usize;
                     self.ip = unsafe { self.ip.add(1) };
-                    if let Values::Obj(obj) = &self.chunk.constants[constant_idx] {
-                        unsafe {
-                            (*(*obj)).next = self.objects;
-                            self.objects = *obj;
-                        }
-                    }
-                    self.stack.push(self.chunk.constants[constant_idx].clone());
+                    let constant = unsafe { *self.chunk().constants.get_unchecked(constant_idx) };
+                    self.stack.push(constant);
                 },
                 OP_CONSTANT_LONG => {
                     let b0 = unsafe { *self.ip } as u32;
@@ -102,123 +456,322 @@ impl VM {
                     let b2 = unsafe { *self.ip.add(2) } as u32;
                     let index = ((b0 << 16) | (b1 << 8) | b2) as usize;
                     self.ip = unsafe { self.ip.add(3) };
-                    if let Values::Obj(obj) = &self.chunk.constants[index] {
-                        unsafe {
-                            (*(*obj)).next = self.objects;
-                            self.objects = *obj;
-                        }
-                    }
-                    self.stack.push(self.chunk.constants[index].clone());
+                    let constant = unsafe { *self.chunk().constants.get_unchecked(index) };
+                    self.stack.push(constant);
                 },
                 OP_NEGATE => {
-                    match self.stack.pop() {
-                        Some(Values::Number(i)) => self.stack.push(Values::Number(-i)),
-                        Some(Values::Bool(i)) => self.stack.push(Values::Bool(!i)),
-                        _ => return InterpretResult::RuntimeError {
-                            message: String::from("You tried to negate the value, which does not support.."),
-                            line,
-                        },
+                    match unsafe { self.stack.pop().unwrap_unchecked() } {
+                        Values::Number(i) => self.stack.push(Values::Number(-i)),
+                        value => return self.runtime_error(RuntimeErrorKind::InvalidOperand { operator: "-", operand: value.type_name() }),
                     }
                 },
+                OP_NOT => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    self.stack.push(Values::Bool(value.is_falsey()));
+                },
                 OP_ADD => {
-                    let b = unsafe { self.stack.pop().unwrap_unchecked() };
-                    let a = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let b = unsafe { *self.stack.get_unchecked(self.stack.len() - 1) };
+                    let a = unsafe { *self.stack.get_unchecked(self.stack.len() - 2) };
 
                     match (a, b) {
-                        (Values::Number(aa), Values::Number(bb)) => self.stack.push(Values::Number(aa + bb)),
-                        (Values::Obj(pa), Values::Obj(pb)) => {
-                            unsafe {
-                                match ((*pa).type_obj, (*pb).type_obj) {
-                                    (ObjType::String, ObjType::String) => {
-                                        let pa: *mut ObjString = pa as *mut ObjString;
-                                        let pb: *mut ObjString = pb as *mut ObjString;
-                                        let cap: usize = (*pa).length + (*pb).length;
-                                        let layout = Layout::array::<u8>(cap).unwrap();
-                                        let ptr: *mut u8 = alloc::alloc(layout);
-                                        std::ptr::copy_nonoverlapping((*pa).chars, ptr, (*pa).length);
-                                        std::ptr::copy_nonoverlapping((*pb).chars, ptr.add((*pa).length), (*pb).length);
-                                        let obj_str_ptr = compiler::make_obj_str(ptr, cap) as *mut Obj;
-                                        (*obj_str_ptr).next = self.objects;
-                                        self.objects = obj_str_ptr;
-                                        self.stack.push(Values::Obj(obj_str_ptr));
-                                    },
-                                    _ => return InterpretResult::RuntimeError {
-                                        message: String::from("You cannot compare non-comparable value! (only numbers and strings)"),
-                                        line,
-                                    },
-                                }
-                            }
-                        },
-                        _ => return InterpretResult::RuntimeError {
-                            message: String::from("You cannot compare non-comparable value! (only numbers and strings)"),
-                            line,
+                        (Values::Number(aa), Values::Number(bb)) => {
+                            self.stack.truncate(self.stack.len() - 2);
+                            self.stack.push(Values::Number(aa + bb));
                         },
+                        // A string on either side converts the other operand with `to_string`.
+                        _ if a.is_string() || b.is_string() => self.concatenate(2),
+                        _ => return self.type_mismatch("+", a, b),
                     }
                 },
-                OP_SUBTRACT => binary_op!(self.stack, -, line),
-                OP_MULTIPLY => binary_op!(self.stack, *, line),
-                OP_DIVIDE => binary_op!(self.stack, /, line),
+                OP_SUBTRACT => binary_op!(self, -),
+                OP_MULTIPLY => binary_op!(self, *),
+                OP_DIVIDE => binary_op!(self, /),
+                OP_MODULO => binary_op!(self, %),
                 OP_TRUE => self.stack.push(Values::Bool(true)),
                 OP_FALSE => self.stack.push(Values::Bool(false)),
                 OP_NIL => self.stack.push(Values::Nil),
-                OP_EQUAL => {
+                OP_EQUAL | OP_NOT_EQUAL => {
                     let b = unsafe { self.stack.pop().unwrap_unchecked() };
                     let a = unsafe { self.stack.pop().unwrap_unchecked() };
-
-                    match (a, b) {
-                        (Values::Number(aa), Values::Number(bb)) => self.stack.push(Values::Bool(aa == bb)),
-                        (Values::Obj(pa), Values::Obj(pb)) => {
-                            unsafe {
-                                match ((*pa).type_obj, (*pb).type_obj) {
-                                    (ObjType::String, ObjType::String) => {
-                                        let pa: *mut ObjString = pa as *mut ObjString;
-                                        let pb: *mut ObjString = pb as *mut ObjString;
-                                        let sa = std::slice::from_raw_parts((*pa).chars, (*pa).length);
-                                        let sb = std::slice::from_raw_parts((*pb).chars, (*pb).length);
-                                        self.stack.push(Values::Bool(sa == sb));
-                                    },
-                                    _ => return InterpretResult::RuntimeError {
-                                        message: String::from("You cannot add non-addable value! (only numbers and strings)"),
-                                        line,
-                                    },
-                                }
-                            }
-                        },
-                        _ => return InterpretResult::RuntimeError {
-                            message: String::from("You cannot add non-addable value! (only numbers and strings)"),
-                            line,
-                        },
+                    let negate = instruction == OP_NOT_EQUAL;
+                    self.stack.push(Values::Bool(a.equals(b) != negate));
+                },
+                OP_GREATER => compare_op!(self, >),
+                OP_LESS => compare_op!(self, <),
+                OP_GREATER_EQUAL => compare_op!(self, >=),
+                OP_LESS_EQUAL => compare_op!(self, <=),
+                OP_POP => {
+                    self.stack.pop();
+                },
+                OP_BUILD_LIST => {
+                    let count = unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let list = self.new_list(items);
+                    self.stack.push(list);
+                },
+                OP_BUILD_MAP => {
+                    let count = unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    let pairs = self.stack.split_off(self.stack.len() - count * 2);
+                    match self.new_map(&pairs) {
+                        Ok(map) => self.stack.push(map),
+                        Err(error) => return self.runtime_error(error),
                     }
                 },
-                OP_GREATER => {
-                    let b = unsafe { self.stack.pop().unwrap_unchecked() };
-                    let a = unsafe { self.stack.pop().unwrap_unchecked() };
-                    match (a, b) {
-                        (Values::Number(a), Values::Number(b)) => self.stack.push(Values::Bool(a > b)),
-                        _ => return InterpretResult::RuntimeError {
-                            message: String::from("You cannot compare non-comparable value! (only numbers)"),
-                            line,
+                OP_GET_INDEX => {
+                    let index = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let target = unsafe { self.stack.pop().unwrap_unchecked() };
+                    match self.get_index(target, index) {
+                        Ok(value) => self.stack.push(value),
+                        Err(error) => return self.runtime_error(error),
+                    }
+                },
+                OP_SET_INDEX => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let index = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let target = unsafe { self.stack.pop().unwrap_unchecked() };
+                    if let Err(error) = self.set_index(target, index, value) {
+                        return self.runtime_error(error);
+                    }
+                    self.stack.push(value);
+                },
+                OP_DUP2 => {
+                    let len = self.stack.len();
+                    let (a, b) = unsafe { (*self.stack.get_unchecked(len - 2), *self.stack.get_unchecked(len - 1)) };
+                    self.stack.push(a);
+                    self.stack.push(b);
+                },
+                OP_TRY => {
+                    let distance = self.read_u16();
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        catch: unsafe { self.ip.add(distance) },
+                    });
+                },
+                OP_END_TRY => {
+                    self.handlers.pop();
+                },
+                OP_OK | OP_ERR => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let result = self.new_result(instruction == OP_OK, value);
+                    self.stack.push(result);
+                },
+                OP_PROPAGATE => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let result = match value {
+                        Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Result) } => unsafe { &*(obj as *mut ObjResult) },
+                        _ => return self.runtime_error(RuntimeErrorKind::InvalidOperand { operator: "?", operand: value.type_name() }),
+                    };
+                    if result.ok {
+                        self.stack.push(result.value);
+                    } else if self.handlers.last().is_some_and(|handler| handler.frames == self.frames.len()) {
+                        self.throw(result.value);
+                    } else if self.frames.len() == 1 || !self.frame().module.is_null() {
+                        // The top level of a file has nothing to return the error to.
+                        return self.runtime_error(RuntimeErrorKind::UncaughtErr(value.to_string()));
+                    } else if self.return_from_frame(value) {
+                        return InterpretResult::Done;
+                    }
+                },
+                OP_IMPORT => {
+                    let path = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let Some(path) = name_string(path) else { return self.runtime_error(RuntimeErrorKind::NotAName); };
+                    if let Err(error) = self.import(path) {
+                        return self.runtime_error(error);
+                    }
+                },
+                OP_GET_PROPERTY => {
+                    let name = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let receiver = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let Some(name) = name_string(name) else { return self.runtime_error(RuntimeErrorKind::NotAName); };
+                    match self.get_property(receiver, name) {
+                        Ok(value) => self.stack.push(value),
+                        Err(error) => return self.runtime_error(error),
+                    }
+                },
+                OP_INVOKE => {
+                    let arg_count = unsafe { *self.ip };
+                    self.ip = unsafe { self.ip.add(1) };
+                    if let Err(error) = self.invoke(arg_count) {
+                        return self.runtime_error(error);
+                    }
+                },
+                OP_JUMP => {
+                    let distance = self.read_u16();
+                    self.ip = unsafe { self.ip.add(distance) };
+                },
+                OP_JUMP_IF_FALSE => {
+                    let distance = self.read_u16();
+                    if unsafe { self.stack.last().unwrap_unchecked() }.is_falsey() {
+                        self.ip = unsafe { self.ip.add(distance) };
+                    }
+                },
+                OP_LOOP => {
+                    let distance = self.read_u16();
+                    self.ip = unsafe { self.ip.sub(distance) };
+                },
+                OP_RANGE | OP_RANGE_INCLUSIVE => {
+                    let end = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let start = unsafe { self.stack.pop().unwrap_unchecked() };
+                    match self.new_range(start, end, instruction == OP_RANGE_INCLUSIVE) {
+                        Ok(range) => self.stack.push(range),
+                        Err(error) => return self.runtime_error(error),
+                    }
+                },
+                OP_ITER => {
+                    let iterable = unsafe { self.stack.pop().unwrap_unchecked() };
+                    match self.make_iterator(iterable) {
+                        Ok(iterator) => self.stack.push(iterator),
+                        Err(error) => return self.runtime_error(error),
+                    }
+                },
+                OP_FOR_ITER => {
+                    let distance = self.read_u16();
+                    let iterator = match unsafe { *self.stack.last().unwrap_unchecked() } {
+                        Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Iterator) } => obj as *mut ObjIterator,
+                        value => return self.runtime_error(RuntimeErrorKind::NotAnIterator(value.type_name())),
+                    };
+                    match self.iterate(iterator) {
+                        Some(item) => self.stack.push(item),
+                        None => self.ip = unsafe { self.ip.add(distance) },
+                    }
+                },
+                // A range pattern: a value of another type than the bounds simply doesn't match.
+                OP_MATCH_RANGE => {
+                    let inclusive = unsafe { *self.ip } != 0;
+                    self.ip = unsafe { self.ip.add(1) };
+                    let end = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let start = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let matched = match (start, value, end) {
+                        (Values::Number(start), Values::Number(value), Values::Number(end)) => in_range(start, value, end, inclusive),
+                        (Values::Obj(start), Values::Obj(value), Values::Obj(end))
+                            if [start, value, end].iter().all(|&obj| Values::Obj(obj).is_string()) => unsafe {
+                            let text = |obj: *mut Obj| ObjString::as_str(obj as *mut ObjString);
+                            in_range(text(start), text(value), text(end), inclusive)
                         },
+                        _ => false,
+                    };
+                    self.stack.push(Values::Bool(matched));
+                },
+                OP_NO_MATCH => {
+                    let value = unsafe { *self.stack.last().unwrap_unchecked() };
+                    return self.runtime_error(RuntimeErrorKind::NoMatch(as_written(value)));
+                },
+                OP_ASSERT => {
+                    let arg_count = unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    let args = self.stack.split_off(self.stack.len() - arg_count);
+                    if args[0].is_falsey() {
+                        return self.abort(RuntimeErrorKind::AssertFailed(args.get(1).map(Values::to_string)));
                     }
+                    self.stack.push(Values::Nil);
                 },
-                OP_LESS => {
-                    let b = unsafe { self.stack.pop().unwrap_unchecked() };
-                    let a = unsafe { self.stack.pop().unwrap_unchecked() };
-                    match (a, b) {
-                        (Values::Number(a), Values::Number(b)) => self.stack.push(Values::Bool(a < b)),
-                        _ => return InterpretResult::RuntimeError {
-                            message: String::from("You cannot compare non-comparable value! (only numbers)"),
-                            line,
-                        }
+                OP_ASSERT_EQ => {
+                    let right = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let left = unsafe { self.stack.pop().unwrap_unchecked() };
+                    if !left.equals(right) {
+                        return self.abort(RuntimeErrorKind::AssertEqFailed { left: as_written(left), right: as_written(right) });
                     }
+                    self.stack.push(Values::Nil);
                 },
-                OP_POP => {
-                    self.stack.pop();
+                OP_PANIC => {
+                    let arg_count = unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    let message = (arg_count == 1).then(|| unsafe { self.stack.last().unwrap_unchecked() }.to_string());
+                    return self.abort(RuntimeErrorKind::Panic(message));
                 },
-                _ => return InterpretResult::RuntimeError {
-                    message : String::from("Fatal! Something wrong happened..."),
-                    line,
-                }
+                OP_PRINT => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let written = match &mut self.captured {
+                        Some(buffer) => writeln!(buffer, "{}", value),
+                        None => writeln!(io::stdout().lock(), "{}", value),
+                    };
+                    match written {
+                        Ok(()) => {},
+                        // Whoever was reading stopped (`reef script.rf | head`); there is no one left to tell.
+                        Err(error) if error.kind() == io::ErrorKind::BrokenPipe => return self.output_closed(),
+                        Err(error) => return self.runtime_error(RuntimeErrorKind::Output(error.kind())),
+                    }
+                },
+                OP_BUILD_STRING => {
+                    let parts = unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    self.concatenate(parts);
+                },
+                OP_DUP => {
+                    let top = unsafe { *self.stack.last().unwrap_unchecked() };
+                    self.stack.push(top);
+                },
+                // Redefining a global is allowed and replaces its mutability and visibility too.
+                OP_DEFINE_GLOBAL | OP_DEFINE_GLOBAL_MUT => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let Some(name) = name_string(unsafe { self.stack.pop().unwrap_unchecked() }) else {
+                        return self.runtime_error(RuntimeErrorKind::NotAName);
+                    };
+                    let key = Values::Obj(name as *mut Obj);
+                    self.globals().table_set(key, value);
+                    if instruction == OP_DEFINE_GLOBAL {
+                        self.immutable_globals().table_set(key, Values::Bool(true));
+                    } else {
+                        self.immutable_globals().table_delete(key);
+                    }
+                    let module = unsafe { (*self.frame().function).module };
+                    if !module.is_null() {
+                        unsafe { (*module).exports.table_delete(key); }
+                    }
+                },
+                // Only a module's exports matter; `pub` in the script the VM was given changes nothing.
+                OP_EXPORT => {
+                    let Some(name) = name_string(unsafe { self.stack.pop().unwrap_unchecked() }) else {
+                        return self.runtime_error(RuntimeErrorKind::NotAName);
+                    };
+                    let module = unsafe { (*self.frame().function).module };
+                    if !module.is_null() {
+                        unsafe { (*module).exports.table_set(Values::Obj(name as *mut Obj), Values::Bool(true)); }
+                    }
+                },
+                OP_GET_GLOBAL => {
+                    let Some(name) = name_string(unsafe { self.stack.pop().unwrap_unchecked() }) else {
+                        return self.runtime_error(RuntimeErrorKind::NotAName);
+                    };
+                    match self.globals().table_get(Values::Obj(name as *mut Obj)) {
+                        Some(value) => self.stack.push(value),
+                        None => return self.undefined_variable(name),
+                    }
+                },
+                OP_SET_GLOBAL => {
+                    let value = unsafe { self.stack.pop().unwrap_unchecked() };
+                    let Some(name) = name_string(unsafe { self.stack.pop().unwrap_unchecked() }) else {
+                        return self.runtime_error(RuntimeErrorKind::NotAName);
+                    };
+                    let key = Values::Obj(name as *mut Obj);
+                    if self.immutable_globals().table_get(key).is_some() {
+                        let name = unsafe { ObjString::as_str(name) }.to_string();
+                        return self.runtime_error(RuntimeErrorKind::ImmutableVariable(name));
+                    }
+                    if self.globals().table_set(key, value) {
+                        self.globals().table_delete(key);
+                        return self.undefined_variable(name);
+                    }
+                    self.stack.push(value);
+                },
+                OP_GET_LOCAL => {
+                    let slot = self.frame().slots + unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    self.stack.push(unsafe { *self.stack.get_unchecked(slot) });
+                },
+                OP_SET_LOCAL => {
+                    let slot = self.frame().slots + unsafe { *self.ip } as usize;
+                    self.ip = unsafe { self.ip.add(1) };
+                    unsafe {
+                        let value = *self.stack.last().unwrap_unchecked();
+                        *self.stack.get_unchecked_mut(slot) = value;
+                    }
+                },
+                _ => unreachable!("verified chunks only contain known opcodes"),
             }
         }
     }
@@ -228,16 +781,7 @@ impl VM {
         unsafe {
             while !object.is_null() {
                 let next: *mut Obj = (*object).next;
-                match (*object).type_obj {
-                    ObjType::String => {
-                        let s = object as *mut ObjString;
-                        let layout = Layout::array::<u8>((*s).length).unwrap();
-                        alloc::dealloc((*s).chars, layout);
-                        let layout = Layout::new::<ObjString>();
-                        alloc::dealloc(s as *mut u8, layout);
-                    },
-                    _ => panic!("memory --> leaked!!!!!!"),
-                }
+                Obj::free(object);
                 object = next;
             }
         }
@@ -250,3 +794,20 @@ impl Drop for VM {
     }
 }
 
+/// Compiled code only names globals and methods with string constants, but the verifier doesn't track
+/// value types, so a hand-built chunk can put anything there.
+fn name_string(value: Values) -> Option<*mut ObjString> {
+    match value {
+        Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::String) } => Some(obj as *mut ObjString),
+        _ => None,
+    }
+}
+
+/// Strings quoted, everything else as `print` shows it.
+fn as_written(value: Values) -> String {
+    if value.is_string() { format!("\"{}\"", value) } else { value.to_string() }
+}
+
+fn in_range<T: PartialOrd>(start: T, value: T, end: T, inclusive: bool) -> bool {
+    start <= value && if inclusive { value <= end } else { value < end }
+}
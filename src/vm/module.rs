@@ -0,0 +1,94 @@
+use super::{VM, FRAMES_MAX};
+use super::error::RuntimeErrorKind;
+use crate::chunk::{Values, Obj, ObjType, ObjString, ObjModule, verify};
+use crate::compiler;
+use std::path::{Path, PathBuf};
+
+impl VM {
+    /// `OP_IMPORT`: pushes the module at `relative`, a path from the importing file's directory.
+    /// The first import compiles the file and starts its top-level code in a new frame; when that
+    /// frame returns it leaves the module on the stack, so either way the next instruction sees the module.
+    pub(super) fn import(&mut self, relative: *mut ObjString) -> Result<(), RuntimeErrorKind> {
+        let relative = unsafe { ObjString::as_str(relative) };
+        let load_error = |reason: String| RuntimeErrorKind::ModuleLoad { path: relative.to_string(), reason };
+        let resolved = self.importing_dir().join(relative).canonicalize().map_err(|error| load_error(error.to_string()))?;
+        let file = resolved.to_str().ok_or_else(|| load_error("its path is not valid UTF-8".to_string()))?;
+        // Modules are cached under their resolved path, so every way of reaching a file shares one module.
+        let path = unsafe { compiler::make_obj_str(file.as_ptr(), file.len()) };
+        unsafe { self.link(path as *mut Obj); }
+
+        if let Some(Values::Obj(module)) = self.modules.table_get(Values::Obj(path as *mut Obj)) {
+            let module = module as *mut ObjModule;
+            if unsafe { !(*module).loaded } {
+                return Err(RuntimeErrorKind::ImportCycle(self.import_chain(module)));
+            }
+            self.stack.push(Values::Obj(module as *mut Obj));
+            return Ok(());
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeErrorKind::StackOverflow);
+        }
+
+        let chunk = match compiler::compile_file(Path::new(file), self.opt_level) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return Err(load_error("it has compile errors".to_string())),
+            Err(error) => return Err(load_error(error.to_string())),
+        };
+
+        let name = Path::new(file).file_stem().and_then(|stem| stem.to_str()).unwrap_or("module");
+        let body_name = format!("<module {}>", name);
+        let module = unsafe { compiler::make_obj_module(compiler::make_obj_str(name.as_ptr(), name.len()), path) };
+        let body = unsafe { compiler::make_obj_function(compiler::make_obj_str(body_name.as_ptr(), body_name.len()), 0, chunk) };
+        unsafe {
+            self.link(module as *mut Obj);
+            self.link_function(body, module);
+            verify::verify_function(body).map_err(|error| load_error(error.to_string()))?;
+        }
+
+        self.modules.table_set(Values::Obj(path as *mut Obj), Values::Obj(module as *mut Obj));
+        self.stack.push(Values::Obj(module as *mut Obj));
+        self.push_frame(body, module);
+        Ok(())
+    }
+
+    /// The script's directory, or the directory of the module whose top-level code is running.
+    fn importing_dir(&self) -> PathBuf {
+        let module = unsafe { (*self.frame().function).module };
+        if module.is_null() {
+            return self.script_dir.clone();
+        }
+        let path = Path::new(unsafe { ObjString::as_str((*module).path) });
+        path.parent().map(Path::to_path_buf).unwrap_or_default()
+    }
+
+    /// Names of the modules still loading from `module` inward, ending with `module` again.
+    fn import_chain(&self, module: *mut ObjModule) -> Vec<String> {
+        let name = |module: *mut ObjModule| unsafe { ObjString::as_str((*module).name) }.to_string();
+        let mut chain: Vec<String> = self.frames.iter()
+            .map(|frame| frame.module)
+            .skip_while(|&loading| loading != module)
+            .filter(|loading| !loading.is_null())
+            .map(name)
+            .collect();
+        chain.push(name(module));
+        chain
+    }
+
+    /// `OP_GET_PROPERTY`: only modules have properties, the `pub` globals their top-level code defined.
+    pub(super) fn get_property(&self, receiver: Values, name: *mut ObjString) -> Result<Values, RuntimeErrorKind> {
+        let module = match receiver {
+            Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Module) } => obj as *mut ObjModule,
+            _ => return Err(RuntimeErrorKind::NoProperties(receiver.type_name())),
+        };
+        let key = Values::Obj(name as *mut Obj);
+        unsafe {
+            let value = (*module).globals.table_get(key);
+            if let Some(value) = value && (*module).exports.table_get(key).is_some() {
+                return Ok(value);
+            }
+            let module = ObjString::as_str((*module).name).to_string();
+            let name = ObjString::as_str(name).to_string();
+            Err(if value.is_some() { RuntimeErrorKind::PrivateItem { module, name } } else { RuntimeErrorKind::UndefinedItem { module, name } })
+        }
+    }
+}
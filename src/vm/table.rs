@@ -1,61 +1,99 @@
 use crate::chunk::{Values, ObjString};
+use crate::hash;
 use std::alloc::{self, Layout};
 
+/// Keys are any value `hash_key` accepts and are compared with `Values::equals`.
+/// `count` includes tombstones (it drives resizing); `len` is the number of live entries.
 pub struct Table {
     count: usize,
+    len: usize,
     capacity: usize,
     entries: *mut Entry,
 }
 
+/// `key` is `None` for an empty slot (value `Nil`) or a tombstone (value `Tombstone`).
 pub struct Entry {
-    key: *mut ObjString,
+    key: Option<Values>,
     value: Values,
  }
 
+/// `None` for values that can't be keys: NaN, which is never equal to itself, and every
+/// object except strings, which compare by identity. `-0.0` hashes like `0.0` because the two are equal.
+pub fn hash_key(key: Values) -> Option<u32> {
+    match key {
+        Values::Number(num) if num.is_nan() => None,
+        Values::Number(num) => {
+            let bits = if num == 0.0 { 0u64 } else { num.to_bits() };
+            let bytes = bits.to_le_bytes();
+            Some(hash::fnv1a_hash(bytes.as_ptr(), bytes.len()))
+        },
+        Values::Bool(b) => Some(b as u32 + 1),
+        Values::Nil => Some(0),
+        Values::Obj(obj) if key.is_string() => Some(unsafe { ObjString::hash(obj as *mut ObjString) }),
+        _ => None,
+    }
+}
+
 impl Table {
     pub fn new() -> Self {
         Self {
             count: 0,
+            len: 0,
             capacity: 0,
             entries: std::ptr::null_mut(),
         }
     }
 
-    pub fn table_set(&mut self, key: *mut ObjString, value: Values) -> bool {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Live entries in slot order, which is unrelated to insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (Values, Values)> + '_ {
+        (0..self.capacity).filter_map(|i| {
+            let entry = unsafe { &*self.entries.add(i) };
+            entry.key.map(|key| (key, entry.value))
+        })
+    }
+
+    /// Returns true when `key` was not in the table yet. `key` must pass `hash_key`.
+    pub fn table_set(&mut self, key: Values, value: Values) -> bool {
         // Load factor: 0.75
-        if (self.count + 1) * 3 > self.capacity * 4 {
+        if (self.count + 1) * 4 > self.capacity * 3 {
             let new_capacity = if self.capacity < 8 { 8 } else { self.capacity * 2 };
             self.adjust_capacity(new_capacity);
         }
 
         unsafe {
-            let entry: *mut Entry = self.find_entry(self.entries, key);
-            let is_new_key: bool = (*entry).key.is_null();
+            let entry: *mut Entry = Table::find_entry(self.entries, self.capacity, key);
+            let is_new_key: bool = (*entry).key.is_none();
             if is_new_key && (*entry).value == Values::Nil { self.count += 1; }
+            if is_new_key { self.len += 1; }
 
-            (*entry).key = key;
+            (*entry).key = Some(key);
             (*entry).value = value;
-            return is_new_key;
+            is_new_key
         }
     }
 
-    pub fn find_entry(&self, entries: *mut Entry, key: *mut ObjString) -> *mut Entry {
-        let capacity = self.capacity;
+    /// `capacity` belongs to `entries`, which is not yet `self.entries` while resizing.
+    pub fn find_entry(entries: *mut Entry, capacity: usize, key: Values) -> *mut Entry {
+        let hash = hash_key(key).expect("table keys are checked with hash_key");
         unsafe {
-            let mut index: usize = ((*key).hash as usize) % capacity;
+            let mut index: usize = (hash as usize) % capacity;
             let mut tombstone: *mut Entry = std::ptr::null_mut();
 
             loop {
                 let entry = entries.add(index);
-                let entry_key = (*entry).key;
-
-                if entry_key.is_null() {
-                    match (*entry).value {
+                match (*entry).key {
+                    None => match (*entry).value {
                         Values::Nil => return if !tombstone.is_null() { tombstone } else { entry },
-                        Values::Tombstone => if tombstone.is_null() { tombstone = entry },
+                        Values::Tombstone if tombstone.is_null() => tombstone = entry,
                         _ => {},
-                    }
-                } else if ObjString::equals(entry_key, key) { return entry; }
+                    },
+                    Some(entry_key) if entry_key.equals(key) => return entry,
+                    Some(_) => {},
+                }
 
                 index = (index + 1) % capacity;
             }
@@ -67,67 +105,67 @@ impl Table {
             let layout = Layout::array::<Entry>(capacity).unwrap();
             let entries: *mut Entry = alloc::alloc(layout) as *mut Entry;
             for i in 0..capacity {
-                (*entries.add(i)).key = std::ptr::null_mut();
+                (*entries.add(i)).key = None;
                 (*entries.add(i)).value = Values::Nil;
             }
             self.count = 0;
 
             for i in 0..self.capacity {
                 let old_entry: *mut Entry = self.entries.add(i);
-                if (*old_entry).key.is_null() { continue; }
+                let Some(key) = (*old_entry).key else { continue; };
 
-                let dest = self.find_entry(entries, (*old_entry).key);
+                let dest = Table::find_entry(entries, capacity, key);
                 (*dest).key = (*old_entry).key;
                 (*dest).value = (*old_entry).value;
                 self.count += 1;
             }
 
-            let old_layout = Layout::array::<Entry>(self.capacity).unwrap();
-            alloc::dealloc(self.entries as *mut u8, old_layout);
+            if !self.entries.is_null() {
+                let old_layout = Layout::array::<Entry>(self.capacity).unwrap();
+                alloc::dealloc(self.entries as *mut u8, old_layout);
+            }
 
             self.entries = entries;
             self.capacity = capacity;
         }
     }
 
+    #[allow(dead_code)]
     pub fn table_add_all(&self, to: &mut Table) {
         unsafe {
             for i in 0..self.capacity {
                 let entry = self.entries.add(i);
-                if !(*entry).key.is_null() {
-                    to.table_set((*entry).key, (*entry).value);
+                if let Some(key) = (*entry).key {
+                    to.table_set(key, (*entry).value);
                 }
             }
         }
     }
 
-    pub fn table_get(&self, key: *mut ObjString) -> Option<Values> {
+    pub fn table_get(&self, key: Values) -> Option<Values> {
         if self.count == 0 {
             return None;
         }
 
         unsafe {
-            let entry = self.find_entry(self.entries, key);
-            if (*entry).key.is_null() {
-                None
-            } else {
-                Some((*entry).value.clone())
-            }
+            let entry = Table::find_entry(self.entries, self.capacity, key);
+            (*entry).key.map(|_| (*entry).value)
         }
     }
 
-    pub fn table_delete(&mut self, key: *mut ObjString) -> bool {
+    pub fn table_delete(&mut self, key: Values) -> bool {
         unsafe {
             if self.count == 0 {
                 return false;
             }
 
-            let entry = self.find_entry(self.entries, key);
-            if (*entry).key.is_null() {
+            let entry = Table::find_entry(self.entries, self.capacity, key);
+            if (*entry).key.is_none() {
                 return false;
             }
 
-            (*entry).key = std::ptr::null_mut();
+            self.len -= 1;
+            (*entry).key = None;
             // add Tombstone
             (*entry).value = Values::Tombstone;
             true
@@ -142,6 +180,7 @@ impl Table {
             }
             self.entries = std::ptr::null_mut();
             self.count = 0;
+            self.len = 0;
             self.capacity = 0;
         }
     }
@@ -153,13 +192,3 @@ impl Drop for Table {
     }
 }
 
-impl ObjString {
-    pub fn equals(a: *mut ObjString, b: *mut ObjString) -> bool {
-        unsafe {
-            if (*a).length != (*b).length { return false; }
-            std::slice::from_raw_parts((*a).chars, (*a).length) ==
-                std::slice::from_raw_parts((*b).chars, (*b).length)
-        }
-    }
-}
-
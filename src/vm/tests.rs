@@ -0,0 +1,636 @@
+use super::{VM, InterpretResult, error::RuntimeErrorKind};
+use crate::chunk::{Chunk, Values, Obj, ObjType, ObjString, verify::MAX_FUNCTION_DEPTH};
+use crate::compiler::{self, Compiler, make_obj_str};
+use crate::lexer::Lexer;
+use crate::opcode::*;
+use crate::optimizer::OptLevel;
+use std::fs;
+
+fn try_compile(source: &str, opt_level: OptLevel) -> Option<Chunk> {
+    let mut bytes = source.as_bytes().to_vec();
+    bytes.push(b'\0');
+    Compiler::new(Lexer::new(&bytes), opt_level).compile()
+}
+
+/// Compiles without optimizing, so the chunk is exactly what the compiler emitted.
+fn compile(source: &str) -> Chunk {
+    try_compile(source, OptLevel::O0).expect("source compiles")
+}
+
+fn compiles(source: &str) -> bool {
+    try_compile(source, OptLevel::O0).is_some()
+}
+
+fn run(source: &str) -> VM {
+    let mut vm = VM::new(try_compile(source, OptLevel::O1).expect("source compiles")).expect("chunk verifies");
+    assert!(matches!(vm.run(), InterpretResult::Done));
+    vm
+}
+
+/// Runs `source` and returns everything it printed.
+fn output(source: &str) -> String {
+    let mut vm = VM::new(try_compile(source, OptLevel::O1).expect("source compiles")).expect("chunk verifies");
+    vm.capture_output();
+    assert!(matches!(vm.run(), InterpretResult::Done));
+    vm.output().to_string()
+}
+
+/// Runs `source`, which has to stop with a runtime error, and returns the error.
+fn run_error(source: &str) -> RuntimeErrorKind {
+    let mut vm = VM::new(try_compile(source, OptLevel::O1).expect("source compiles")).expect("chunk verifies");
+    let InterpretResult::RuntimeError { error, .. } = vm.run() else { panic!("expected a runtime error") };
+    error
+}
+
+/// Writes `files` into a fresh directory named after `test` and runs `main.rf` from there.
+/// Returns what it printed, or the runtime error it stopped with.
+fn run_files(test: &str, files: &[(&str, &str)]) -> Result<String, RuntimeErrorKind> {
+    let dir = std::env::temp_dir().join(format!("reef-{}-{}", test, std::process::id()));
+    for (name, source) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).expect("creates the directory");
+        fs::write(path, source).expect("writes the file");
+    }
+    let main = dir.join("main.rf");
+    let chunk = compiler::compile_file(&main, OptLevel::O1).expect("reads main.rf").expect("main.rf compiles");
+    let mut vm = VM::new(chunk).expect("chunk verifies");
+    vm.set_script_dir(&dir);
+    vm.capture_output();
+    let result = match vm.run() {
+        InterpretResult::Done => Ok(vm.output().to_string()),
+        InterpretResult::RuntimeError { error, .. } => Err(error),
+        InterpretResult::CompileError | InterpretResult::OutputClosed => unreachable!(),
+    };
+    fs::remove_dir_all(&dir).expect("removes the directory");
+    result
+}
+
+fn global(vm: &VM, name: &str) -> Values {
+    let key = unsafe { make_obj_str(name.as_ptr(), name.len()) };
+    vm.globals.table_get(Values::Obj(key as *mut Obj)).expect("global is defined")
+}
+
+/// Evaluates `expression` into a global and returns its value, or the runtime error.
+/// The result has to be the same with and without optimization.
+fn evaluate(expression: &str) -> Result<Values, RuntimeErrorKind> {
+    let source = format!("let result = {};", expression);
+    let [unoptimized, optimized] = [OptLevel::O0, OptLevel::O1].map(|opt_level| {
+        let chunk = try_compile(&source, opt_level).expect("source compiles");
+        let mut vm = VM::new(chunk).expect("chunk verifies");
+        match vm.run() {
+            InterpretResult::Done => Ok(global(&vm, "result")),
+            InterpretResult::RuntimeError { error, .. } => Err(error),
+            InterpretResult::CompileError | InterpretResult::OutputClosed => unreachable!(),
+        }
+    });
+    assert_eq!(unoptimized, optimized, "-O0 and -O1 disagree on {}", expression);
+    unoptimized
+}
+
+#[test]
+fn not_is_true_only_for_falsey_values() {
+    assert_eq!(evaluate("!3"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("!0"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("!nil"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("!false"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("!!true"), Ok(Values::Bool(true)));
+}
+
+#[test]
+fn negate_only_accepts_numbers() {
+    assert_eq!(evaluate("-3"), Ok(Values::Number(-3.0)));
+    assert_eq!(evaluate("-true"), Err(RuntimeErrorKind::InvalidOperand { operator: "-", operand: "bool" }));
+    assert_eq!(evaluate("-nil"), Err(RuntimeErrorKind::InvalidOperand { operator: "-", operand: "nil" }));
+}
+
+#[test]
+fn comparisons_have_their_own_opcodes() {
+    assert_eq!(evaluate("1 != 2"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("2 != 2"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("3 >= 3"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("2 >= 3"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("3 <= 3"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("4 <= 3"), Ok(Values::Bool(false)));
+    assert!(evaluate("1 >= true").is_err());
+
+    let code = compile("1 != 2; 1 >= 2; 1 <= 2;").code;
+    for opcode in [OP_NOT_EQUAL, OP_GREATER_EQUAL, OP_LESS_EQUAL] {
+        assert!(code.contains(&opcode));
+    }
+    assert!(!code.contains(&OP_NOT));
+}
+
+#[test]
+fn compound_assignment_updates_globals_and_locals() {
+    let vm = run("let mut x = 10; x += 2; x *= 3; x -= 6; x /= 5; x %= 4;");
+    assert_eq!(global(&vm, "x"), Values::Number(2.0));
+
+    let vm = run("let mut result = 0; { let mut y = 3; y *= 5; y += 1; result = y; }");
+    assert_eq!(global(&vm, "result"), Values::Number(16.0));
+}
+
+#[test]
+fn compound_assignment_reads_the_target_once() {
+    let code = compile("let mut x = 1; x += 2;").code;
+    let count = |opcode| code.iter().filter(|&&byte| byte == opcode).count();
+    assert_eq!(count(OP_GET_GLOBAL), 1);
+    assert_eq!(count(OP_SET_GLOBAL), 1);
+    assert_eq!(count(OP_DUP), 1);
+
+    let vm = run("let mut x = 1; let y = x += 2;");
+    assert_eq!(global(&vm, "x"), Values::Number(3.0));
+    assert_eq!(global(&vm, "y"), Values::Number(3.0));
+}
+
+#[test]
+fn only_variables_can_be_assigned() {
+    assert!(compiles("let mut a = 1; a = 2; a += 3;"));
+    assert!(!compiles("1 += 2;"));
+    assert!(!compiles("let a = 1; let b = 2; a + b = 3;"));
+    assert!(!compiles("let a = 1; (a) -= 1;"));
+    assert!(!compiles("{ let a = a; }"));
+}
+
+#[test]
+fn folding_gives_the_unoptimized_result() {
+    assert_eq!(evaluate("1 + 2 * 3 - 4 / 2"), Ok(Values::Number(5.0)));
+    assert_eq!(evaluate("-(-4) % 3"), Ok(Values::Number(1.0)));
+    assert_eq!(evaluate("!(1 < 2)"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("\"a\" + \"b\" == \"ab\""), Ok(Values::Bool(true)));
+    assert!(evaluate("- -true").is_err());
+    assert!(evaluate("- -\"s\"").is_err());
+    assert!(evaluate("1 + nil").is_err());
+}
+
+#[test]
+fn folding_leaves_one_constant() {
+    let chunk = try_compile("1 + 2 * 3;", OptLevel::O1).unwrap();
+    assert_eq!(chunk.code, [OP_CONSTANT, 0, OP_POP, OP_NIL, OP_RETURN]);
+    assert_eq!(chunk.constants, [Values::Number(7.0)]);
+}
+
+#[test]
+fn repeated_constants_share_a_slot() {
+    // The global's name is the same string constant as the literal "a".
+    let chunk = compile("1; 1; 2.5; \"a\"; \"a\"; let mut a = 1; a = 2.5;");
+    assert_eq!(chunk.constants.len(), 3);
+}
+
+#[test]
+fn more_than_65k_constants() {
+    let mut source = String::new();
+    for i in 0..40_000 {
+        source.push_str(&format!("let g{} = {}.5;\n", i, i));
+    }
+
+    let chunk = try_compile(&source, OptLevel::O1).unwrap();
+    assert!(chunk.constants.len() > 65_536);
+    let mut listing = Vec::new();
+    chunk.disassemble(&mut listing).unwrap();
+    assert!(String::from_utf8(listing).unwrap().contains("OP_CONSTANT_LONG"));
+
+    let loaded = Chunk::deserialize(&chunk.serialize()).expect("round-trips");
+    for chunk in [chunk, loaded] {
+        let mut vm = VM::new(chunk).expect("chunk verifies");
+        assert!(matches!(vm.run(), InterpretResult::Done));
+        assert_eq!(global(&vm, "g0"), Values::Number(0.5));
+        assert_eq!(global(&vm, "g39999"), Values::Number(39999.5));
+    }
+}
+
+#[test]
+fn tracing_does_not_change_the_result() {
+    let source = "let mut a = 2;\n{\n  let b = a * 3;\n  a = b - 1;\n}\n";
+    for lines in [None, Some(1..=u32::MAX), Some(3..=3), Some(7..=9)] {
+        let mut vm = VM::new(compile(source)).expect("chunk verifies");
+        vm.set_trace(lines);
+        assert!(matches!(vm.run(), InterpretResult::Done));
+        assert_eq!(global(&vm, "a"), Values::Number(5.0));
+    }
+}
+
+#[test]
+fn calls_return_values() {
+    let vm = run("Fn add(a, b) { return a + b; }\nFn nothing() {}\nlet sum = add(add(1, 2), 3);\nlet none = nothing();\n");
+    assert_eq!(global(&vm, "sum"), Values::Number(6.0));
+    assert_eq!(global(&vm, "none"), Values::Nil);
+
+    let vm = run("let mut r = 0;\n{\n  let k = 3;\n  Fn square(x) { return x * x; }\n  r = square(k) + k;\n}\n");
+    assert_eq!(global(&vm, "r"), Values::Number(12.0));
+}
+
+#[test]
+fn a_local_function_can_name_itself() {
+    let vm = run("let mut r = nil;\n{\n  Fn f() { return f; }\n  r = f();\n}\n");
+    assert!(matches!(global(&vm, "r"), Values::Obj(obj) if unsafe { matches!((*obj).type_obj, ObjType::Function) }));
+}
+
+#[test]
+fn capturing_a_local_is_a_compile_error() {
+    assert!(!compiles("let x = \"global\";\n{\n  let x = 1;\n  Fn f() { return x; }\n}\n"));
+    assert!(!compiles("Fn outer(a) {\n  Fn inner() { return a; }\n}\n"));
+    assert!(!compiles("Fn outer() {\n  let mut a = 1;\n  Fn inner() { a = 2; }\n}\n"));
+    assert!(compiles("let g = 1;\nFn outer(a) {\n  Fn inner(a) { return a + g; }\n  return inner(a);\n}\n"));
+}
+
+#[test]
+fn runtime_errors_carry_a_stack_trace() {
+    let mut vm = VM::new(compile("Fn inner() { return -nil; }\nFn outer() { return inner(); }\nouter();\n")).expect("chunk verifies");
+    let InterpretResult::RuntimeError { line, stack_trace, .. } = vm.run() else { panic!("expected a runtime error") };
+    assert_eq!(line, 1);
+    let frames: Vec<(&str, u32)> = stack_trace.iter().map(|frame| (frame.function.as_str(), frame.line)).collect();
+    assert_eq!(frames, [("inner", 1), ("outer", 2), ("<script>", 3)]);
+}
+
+#[test]
+fn bad_calls_are_runtime_errors() {
+    let cases = [
+        ("Fn f(a) {}\nf();\n", RuntimeErrorKind::Arity { expected: 1, got: 0 }),
+        ("Fn f() {}\nf(1, 2);\n", RuntimeErrorKind::Arity { expected: 0, got: 2 }),
+        ("let x = 1;\nx();\n", RuntimeErrorKind::NotCallable("number")),
+        ("Fn f() { return f(); }\nf();\n", RuntimeErrorKind::StackOverflow),
+    ];
+    for (source, expected) in cases {
+        let mut vm = VM::new(compile(source)).expect("chunk verifies");
+        let InterpretResult::RuntimeError { error, .. } = vm.run() else { panic!("expected a runtime error") };
+        assert_eq!(error, expected);
+    }
+}
+
+#[test]
+fn nesting_is_limited() {
+    let nested = |depth: usize| "Fn f() {\n".repeat(depth) + &"}\n".repeat(depth);
+    assert!(compiles(&nested(MAX_FUNCTION_DEPTH)));
+    assert!(!compiles(&nested(MAX_FUNCTION_DEPTH + 1)));
+}
+
+#[test]
+fn runtime_errors_have_a_kind() {
+    assert_eq!(evaluate("1 - \"a\""), Err(RuntimeErrorKind::TypeMismatch { operator: "-", left: "number", right: "string" }));
+    assert_eq!(evaluate("nil < 1"), Err(RuntimeErrorKind::TypeMismatch { operator: "<", left: "nil", right: "number" }));
+    assert_eq!(evaluate("missing"), Err(RuntimeErrorKind::UndefinedVariable(String::from("missing"))));
+    assert_eq!(evaluate("missing = 1"), Err(RuntimeErrorKind::UndefinedVariable(String::from("missing"))));
+    assert_eq!(RuntimeErrorKind::UndefinedVariable(String::from("x")).to_string(), "undefined variable 'x'");
+}
+
+#[test]
+fn equality_is_defined_for_every_pair_of_values() {
+    assert_eq!(evaluate("nil == nil"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("true == true"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("1 == 1.0"), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("\"ab\" == \"a\" + \"b\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("0 / 0 == 0 / 0"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("1 == true"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("nil == false"), Ok(Values::Bool(false)));
+    assert_eq!(evaluate("\"1\" != 1"), Ok(Values::Bool(true)));
+
+    let vm = run("Fn f() {}\nFn g() {}\nlet same = f == f;\nlet different = f == g;\n");
+    assert_eq!(global(&vm, "same"), Values::Bool(true));
+    assert_eq!(global(&vm, "different"), Values::Bool(false));
+}
+
+#[test]
+fn globals_survive_the_table_growing() {
+    let source: String = (0..100).map(|i| format!("let v{} = {};\n", i, i)).collect();
+    let vm = run(&source);
+    for i in 0..100 {
+        assert_eq!(global(&vm, &format!("v{}", i)), Values::Number(i as f64));
+    }
+}
+
+#[test]
+fn strings_are_ordered_by_their_bytes() {
+    assert_eq!(evaluate("\"apple\" < \"banana\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("\"ab\" > \"a\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("\"Z\" < \"a\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("\"a\" <= \"a\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("\"é\" >= \"z\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("\"a\" < 1"), Err(RuntimeErrorKind::TypeMismatch { operator: "<", left: "string", right: "number" }));
+}
+
+#[test]
+fn print_writes_each_value_on_its_own_line() {
+    assert_eq!(output("print 1;\nprint 0.5;\nprint \"text\";\nprint nil;\nprint 1 < 2;\n"), "1\n0.5\ntext\nnil\ntrue\n");
+    assert_eq!(output("print 0 / 0;\nprint -1 / 0;\n"), "NaN\n-inf\n");
+}
+
+#[test]
+fn strings_interpolate_expressions() {
+    assert_eq!(output("let n = 3;\nprint \"count: {n}, next: {n + 1}\";\n"), "count: 3, next: 4\n");
+    assert_eq!(output("print \"{\"in\" + \"ner\"}!\";\n"), "inner!\n");
+    assert_eq!(output("print \"literal \\{n}\";\n"), "literal {n}\n");
+}
+
+#[test]
+fn adding_to_a_string_converts_the_other_side() {
+    assert_eq!(output("print \"count: \" + 3;\nprint 1.5 + \"!\";\nprint \"\" + nil + true;\n"), "count: 3\n1.5!\nniltrue\n");
+    assert_eq!(evaluate("1 + true"), Err(RuntimeErrorKind::TypeMismatch { operator: "+", left: "number", right: "bool" }));
+}
+
+#[test]
+fn a_string_chain_is_built_once() {
+    let mut listing = Vec::new();
+    compile("let x = 1;\nlet y = \"a\" + x + 2 * 3 + nil;\n").disassemble(&mut listing).unwrap();
+    let listing = String::from_utf8(listing).unwrap();
+    assert!(!listing.contains("OP_ADD"));
+    assert_eq!(listing.matches("OP_BUILD_STRING").count(), 1);
+    assert_eq!(output("let x = 1;\nprint \"a\" + x + 2 * 3 + nil;\nprint 1 + 2 + \"a\" + 1 + 2;\n"), "a16nil\n3a12\n");
+
+    let chunk = try_compile("\"a\" + \"b\" + \"c\";", OptLevel::O1).unwrap();
+    assert_eq!(chunk.code, [OP_CONSTANT, 0, OP_POP, OP_NIL, OP_RETURN]);
+    assert_eq!(evaluate("\"a\" + \"b\" + \"c\" == \"abc\""), Ok(Values::Bool(true)));
+}
+
+#[test]
+fn long_string_chains_are_split() {
+    let source = format!("let x = 1;\nlet s = \"\"{};\n", " + x".repeat(600));
+    let vm = run(&source);
+    let Values::Obj(obj) = global(&vm, "s") else { panic!("expected a string") };
+    assert_eq!(unsafe { ObjString::as_str(obj as *mut ObjString) }, "1".repeat(600));
+}
+
+#[test]
+fn lists_are_indexed_and_updated() {
+    assert_eq!(output("let xs = [1, 2, 3];\nxs[0] = 10;\nxs[1] += 1;\nxs.push(4);\nprint xs;\nprint xs[3];\n"), "[10, 3, 3, 4]\n4\n");
+    assert_eq!(output("let xs = [[1], \"a\", nil];\nprint xs;\nprint [];\n"), "[[1], \"a\", nil]\n[]\n");
+    assert_eq!(evaluate("[1, 2][2]"), Err(RuntimeErrorKind::IndexOutOfRange { index: 2.0, len: 2 }));
+    assert_eq!(evaluate("[1, 2][-1]"), Err(RuntimeErrorKind::NegativeIndex(-1.0)));
+    assert_eq!(evaluate("[1, 2][0.5]"), Err(RuntimeErrorKind::FractionalIndex(0.5)));
+    assert_eq!(evaluate("[1, 2][\"0\"]"), Err(RuntimeErrorKind::IndexType { target: "list", index: "string" }));
+    assert_eq!(evaluate("3[0]"), Err(RuntimeErrorKind::NotIndexable("number")));
+}
+
+#[test]
+fn index_assignment_evaluates_its_operands_once() {
+    let source = "let mut calls = 0;\nFn at() { calls += 1; return 1; }\nlet xs = [1, 2];\nxs[at()] += 5;\nprint xs;\nprint calls;\n";
+    assert_eq!(output(source), "[1, 7]\n1\n");
+}
+
+#[test]
+fn lists_have_methods() {
+    let source = "let xs = [1, 2, 3];\nprint xs.pop();\nxs.insert(0, 0);\nprint xs.remove(1);\nprint xs.len();\nprint xs.contains(2);\nprint xs.contains(\"2\");\nprint xs.slice(0, 1);\nprint xs;\n";
+    assert_eq!(output(source), "3\n1\n2\ntrue\nfalse\n[0]\n[0, 2]\n");
+    assert_eq!(evaluate("[].pop()"), Err(RuntimeErrorKind::PopEmpty));
+    assert_eq!(evaluate("[1, 2, 3].slice(2, 1)"), Err(RuntimeErrorKind::InvalidSlice { start: 2, end: 1 }));
+    assert_eq!(evaluate("[].push()"), Err(RuntimeErrorKind::Arity { expected: 1, got: 0 }));
+    assert_eq!(evaluate("[].shuffle()"), Err(RuntimeErrorKind::UndefinedMethod { receiver: "list", name: String::from("shuffle") }));
+    assert_eq!(evaluate("nil.len()"), Err(RuntimeErrorKind::UndefinedMethod { receiver: "nil", name: String::from("len") }));
+}
+
+#[test]
+fn maps_take_hashable_keys() {
+    let source = "let m = {\"a\": 1, 2: \"two\", true: nil};\nm[\"b\"] = 5;\nm[\"a\"] += 1;\nprint m[\"a\"];\nprint m[2];\nprint m[true];\nprint m.has(\"b\");\nprint m.remove(\"b\");\nprint m.has(\"b\");\nprint m.len();\n";
+    assert_eq!(output(source), "2\ntwo\nnil\ntrue\n5\nfalse\n3\n");
+    assert_eq!(evaluate("{0: \"zero\"}[-0] == \"zero\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("{nil: 1}[nil] + {1: 2}[1.0]"), Ok(Values::Number(3.0)));
+    assert_eq!(evaluate("{\"a\": 1}[\"b\"]"), Err(RuntimeErrorKind::MissingKey(String::from("\"b\""))));
+    assert_eq!(evaluate("{}[0 / 0]"), Err(RuntimeErrorKind::UnhashableKey("NaN")));
+    assert_eq!(evaluate("{}[[]]"), Err(RuntimeErrorKind::UnhashableKey("list")));
+}
+
+#[test]
+fn map_keys_and_values_line_up() {
+    let source = "let m = {\"x\": 1, \"y\": 2, \"z\": 3};\nlet ks = m.keys();\nlet vs = m.values();\nprint m[ks[0]] == vs[0];\nprint m[ks[1]] == vs[1];\nprint m[ks[2]] == vs[2];\nprint ks.len();\n";
+    assert_eq!(output(source), "true\ntrue\ntrue\n3\n");
+}
+
+#[test]
+fn while_loops_until_the_condition_is_falsey() {
+    assert_eq!(output("let mut i = 0;\nwhile i < 3 {\n  print i;\n  i += 1;\n}\nwhile nil { print \"never\"; }\n"), "0\n1\n2\n");
+}
+
+#[test]
+fn for_walks_lists_maps_strings_and_ranges() {
+    assert_eq!(output("for x in [1, \"a\", nil] { print x; }\n"), "1\na\nnil\n");
+    assert_eq!(output("for k in {\"only\": 1} { print k; }\n"), "only\n");
+    assert_eq!(output("for c in \"hé!\" { print c; }\n"), "h\né\n!\n");
+    assert_eq!(output("for n in 0..3 { print n; }\nfor n in 1..=2 { print n; }\nfor n in 3..1 { print n; }\n"), "0\n1\n2\n1\n2\n");
+    assert_eq!(output("let r = 0.5..2;\nfor n in r { print n; }\nfor n in r { print n; }\n"), "0.5\n1.5\n0.5\n1.5\n");
+    // Lists are read live, so changes made by the body are seen.
+    assert_eq!(output("let xs = [1, 2, 3];\nfor x in xs { print x; xs.pop(); }\nprint xs;\n"), "1\n2\n[1]\n");
+    assert_eq!(run_error("for x in 3 {}\n"), RuntimeErrorKind::NotIterable("number"));
+}
+
+#[test]
+fn ranges_stop_once_counting_stops_changing_the_number() {
+    // 2^53 + 1 rounds back to 2^53, so each of these could only repeat a value forever.
+    assert_eq!(output("for n in 9007199254740992..9007199254741000 { print n; }\n"), "9007199254740992\n");
+    assert_eq!(output("let big = 9007199254740991;\nfor n in big..=big + 5 { print n; }\n"), "9007199254740991\n9007199254740992\n");
+    assert_eq!(output("for n in -(1 / 0)..0 { print n; }\nfor n in 0..(0 / 0) { print n; }\n"), "-inf\n");
+    assert_eq!(evaluate("1..\"2\""), Err(RuntimeErrorKind::TypeMismatch { operator: "..", left: "number", right: "string" }));
+}
+
+#[test]
+fn c_style_for_runs_its_clauses_in_order() {
+    assert_eq!(output("for (let mut i = 0; i < 3; i += 1) { print i; }\n"), "0\n1\n2\n");
+    assert_eq!(output("let mut i = 10;\nfor (i = 0; i < 2;) { print i; i += 1; }\nprint i;\n"), "0\n1\n2\n");
+    // The loop's `let` is gone afterwards, so the global `i` is untouched.
+    assert_eq!(output("let i = \"outer\";\nfor (let mut i = 0; i < 1; i += 1) {}\nprint i;\n"), "outer\n");
+}
+
+#[test]
+fn loops_give_the_same_output_at_every_level() {
+    let source = "let mut total = 0;\nfor n in 1..=4 {\n  let sq = n * n;\n  total += sq + 2 * 3;\n}\nlet mut i = 0;\nwhile i < 2 + 1 { i += 1; }\nprint total;\nprint i;\n";
+    let unoptimized = {
+        let mut vm = VM::new(compile(source)).expect("chunk verifies");
+        vm.capture_output();
+        assert!(matches!(vm.run(), InterpretResult::Done));
+        vm.output().to_string()
+    };
+    assert_eq!(unoptimized, "54\n3\n");
+    assert_eq!(output(source), unoptimized);
+}
+
+#[test]
+fn break_pops_the_locals_of_the_loop_body() {
+    let source = "{\n  let a = \"outer\";\n  while true {\n    let b = 1;\n    { let c = 2; break; }\n  }\n  let d = \"after\";\n  print a;\n  print d;\n}\n";
+    assert_eq!(output(source), "outer\nafter\n");
+    let source = "{\n  let a = \"outer\";\n  for x in [1, 2, 3] {\n    let y = x * 2;\n    break;\n  }\n  let d = \"after\";\n  print a + d;\n}\n";
+    assert_eq!(output(source), "outerafter\n");
+    // Each unpopped local would shift every later slot, so the verifier would also catch it.
+    assert!(compile("{ let a = 1; while true { let b = 2; let c = 3; break; } let d = a; }").verify(0).is_ok());
+}
+
+#[test]
+fn continue_goes_to_the_next_iteration() {
+    assert_eq!(output("for n in 0..4 {\n  let mut m = n;\n  while m > 0 { m -= 1; continue; }\n  print n;\n  continue;\n  print \"never\";\n}\n"), "0\n1\n2\n3\n");
+    assert_eq!(output("let mut i = 0;\nwhile i < 3 { i += 1; continue; }\nprint i;\n"), "3\n");
+    // `continue` in a C-style loop still runs the step.
+    assert_eq!(output("for (let mut i = 0; i < 3; i += 1) { print i; continue; }\n"), "0\n1\n2\n");
+    assert_eq!(output("for (let mut i = 0; i < 9; i += 1) { print i; break; }\n"), "0\n");
+}
+
+#[test]
+fn break_and_continue_need_a_loop() {
+    assert!(!compiles("break;"));
+    assert!(!compiles("{ continue; }"));
+    assert!(!compiles("while true {\n  Fn f() { break; }\n}\n"));
+    assert!(compiles("Fn f() { while true { break; } }"));
+}
+
+#[test]
+fn match_picks_the_first_accepting_arm() {
+    let source = "Fn size(n) {\n  return match n {\n    0 => \"none\",\n    1 | 2 | 3 => \"few\",\n    4..10 => \"some\",\n    10..=12 => \"dozen\",\n    \"many\" => \"many!\",\n    other => \"lots: {other}\",\n  };\n}\nfor n in [0, 2, 9, 10, 12, \"many\", 13, nil] { print size(n); }\n";
+    assert_eq!(output(source), "none\nfew\nsome\ndozen\ndozen\nmany!\nlots: 13\nlots: nil\n");
+    assert_eq!(evaluate("match \"a\" { 0..9 => 1, _ => 2 }"), Ok(Values::Number(2.0)));
+    assert_eq!(evaluate("match true { false => 1, true => 2 }"), Ok(Values::Number(2.0)));
+}
+
+#[test]
+fn an_unmatched_match_is_an_error() {
+    assert_eq!(evaluate("match 5 { 0 => 1, 1..3 => 2 }"), Err(RuntimeErrorKind::NoMatch(String::from("5"))));
+    assert_eq!(evaluate("match \"x\" { \"y\" => 1 }"), Err(RuntimeErrorKind::NoMatch(String::from("\"x\""))));
+}
+
+#[test]
+fn match_bindings_are_scoped_to_their_arm() {
+    let source = "let x = \"global\";\n{\n  let a = 1;\n  let r = match a + 1 { x => x * 10 };\n  print r;\n  print a;\n}\nprint x;\n";
+    assert_eq!(output(source), "20\n1\nglobal\n");
+    assert_eq!(run_error("let r = match 1 { y => y };\nprint y;\n"), RuntimeErrorKind::UndefinedVariable(String::from("y")));
+}
+
+#[test]
+fn if_and_blocks_have_values() {
+    assert_eq!(evaluate("if 1 < 2 { \"yes\" } else { \"no\" } == \"yes\""), Ok(Values::Bool(true)));
+    assert_eq!(evaluate("if false { 1 }"), Ok(Values::Nil));
+    assert_eq!(evaluate("if nil { 1 } else if 0 { 2 } else { 3 }"), Ok(Values::Number(2.0)));
+    assert_eq!(evaluate("{ let w = 3; let h = 4; w * h }"), Ok(Values::Number(12.0)));
+    assert_eq!(evaluate("{ let w = 3; w * 2; }"), Ok(Values::Nil));
+    // `{}` in expression position is an empty map, not an empty block.
+    assert_eq!(output("let m = {};\nm[\"k\"] = 1;\nprint m;\nprint {\"a\": 1,};\n"), "{\"k\": 1}\n{\"a\": 1}\n");
+}
+
+#[test]
+fn if_statements_pick_one_branch() {
+    let source = "for x in [-2, 0, 5] {\n  if x > 0 {\n    print \"positive\";\n  } else if x == 0 {\n    print \"zero\";\n  } else {\n    print \"negative\";\n  }\n}\n";
+    assert_eq!(output(source), "negative\nzero\npositive\n");
+    // Locals of a statement branch are popped without disturbing the ones around it.
+    assert_eq!(output("{\n  let a = 1;\n  if true { let b = 2; let c = 3; }\n  let d = 4;\n  print a + d;\n}\n"), "5\n");
+}
+
+#[test]
+fn function_bodies_return_their_last_expression() {
+    let source = "Fn sub(a, b) { a - b }\nFn sign(x) { if x < 0 { -1 } else { 1 } }\nFn unit() { 1; }\nFn early(x) { if x { return \"early\"; } \"late\" }\nprint sub(5, 2);\nprint sign(-3);\nprint unit();\nprint early(true);\nprint early(false);\n";
+    assert_eq!(output(source), "3\n-1\nnil\nearly\nlate\n");
+    let source = "Fn area() {\n  let w = 3;\n  let h = 4;\n  w * h\n}\nlet x = area() + 1;\nprint x;\n";
+    assert_eq!(output(source), "13\n");
+}
+
+#[test]
+fn bindings_are_immutable_unless_mut() {
+    assert!(!compiles("{ let a = 1; a = 2; }"));
+    assert!(!compiles("{ let a = 1; a += 2; }"));
+    assert!(!compiles("Fn f(n) { n += 1; }"));
+    assert!(!compiles("for x in [1] { x = 2; }"));
+    assert!(!compiles("for (let i = 0; i < 3; i += 1) {}"));
+    assert!(!compiles("let r = match 1 { y => { y = 2; y } };"));
+    assert!(compiles("{ let mut a = 1; a = 2; }\nFn f(mut n) { n += 1; return n; }\nfor (let mut i = 0; i < 3; i += 1) {}\n"));
+    assert_eq!(output("Fn bump(mut n) { n += 1; n }\nprint bump(1);\n"), "2\n");
+}
+
+#[test]
+fn immutable_globals_are_checked_when_assigned() {
+    assert_eq!(run_error("let limit = 10;\nlimit = 11;\n"), RuntimeErrorKind::ImmutableVariable(String::from("limit")));
+    assert_eq!(run_error("Fn f() {}\nf = 1;\n"), RuntimeErrorKind::ImmutableVariable(String::from("f")));
+    // A function may assign a global declared after it, so only running it can tell.
+    assert_eq!(run_error("Fn set() { later = 2; }\nlet later = 1;\nset();\n"), RuntimeErrorKind::ImmutableVariable(String::from("later")));
+    assert_eq!(output("Fn set() { later = 2; }\nlet mut later = 1;\nset();\nprint later;\n"), "2\n");
+    // Redeclaring decides again.
+    assert_eq!(output("let a = 1;\nlet mut a = 2;\na += 1;\nprint a;\nlet a = 0;\nprint a;\n"), "3\n0\n");
+    assert_eq!(run_error("let mut a = 1;\nlet a = 2;\na = 3;\n"), RuntimeErrorKind::ImmutableVariable(String::from("a")));
+}
+
+#[test]
+fn modules_are_loaded_once_and_read_through_their_name() {
+    let files = [
+        ("main.rf", "use utils::math;\nuse \"utils/math.rf\";\nprint math.area(2);\nprint math.calls;\n"),
+        ("utils/math.rf", "use consts;\npub let calls = 1;\nprint \"loading math\";\npub Fn area(r) { consts.pi * r * r }\n"),
+        ("utils/consts.rf", "pub let pi = 3;\n"),
+    ];
+    assert_eq!(run_files("modules", &files), Ok(String::from("loading math\n12\n1\n")));
+    let files = [("main.rf", "use other;\nlet m = other;\nprint m.missing;\n"), ("other.rf", "pub let present = 1;\n")];
+    assert_eq!(run_files("missing-item", &files), Err(RuntimeErrorKind::UndefinedItem { module: String::from("other"), name: String::from("missing") }));
+}
+
+#[test]
+fn import_cycles_are_reported() {
+    let files = [("main.rf", "use a;\n"), ("a.rf", "use b;\n"), ("b.rf", "use a;\n")];
+    assert_eq!(run_files("cycle", &files), Err(RuntimeErrorKind::ImportCycle(vec![String::from("a"), String::from("b"), String::from("a")])));
+}
+
+#[test]
+fn only_pub_items_can_be_imported() {
+    let files = [
+        ("main.rf", "use math;\nprint math.area(2);\nlet m = math;\nprint m.square(2);\n"),
+        ("math.rf", "pub Fn area(r) { 3 * square(r) }\nFn square(x) { x * x }\n"),
+    ];
+    assert_eq!(run_files("private-runtime", &files), Err(RuntimeErrorKind::PrivateItem { module: String::from("math"), name: String::from("square") }));
+
+    // Naming the module directly is checked while compiling the importing file.
+    let dir = std::env::temp_dir().join(format!("reef-private-compile-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creates the directory");
+    fs::write(dir.join("math.rf"), "pub let pi = 3;\nlet secret = 1;\n{ let inner = 2; }\n").expect("writes the file");
+    let compiles_in_dir = |source: &str| {
+        fs::write(dir.join("main.rf"), source).expect("writes the file");
+        compiler::compile_file(&dir.join("main.rf"), OptLevel::O0).expect("reads main.rf").is_some()
+    };
+    assert!(compiles_in_dir("use math;\nprint math.pi;\n"));
+    assert!(!compiles_in_dir("use math;\nprint math.secret;\n"));
+    assert!(!compiles_in_dir("use math;\nprint math.inner;\n"));
+    assert!(!compiles_in_dir("{ pub let a = 1; }\n"));
+    // A global that replaces the module is no longer checked against it.
+    assert!(compiles_in_dir("use math;\nlet math = 1;\nprint math.secret;\n"));
+    fs::remove_dir_all(&dir).expect("removes the directory");
+}
+
+#[test]
+fn question_mark_returns_errors_to_the_caller() {
+    let source = "\
+Fn parse(s) { if s == \"1\" { Ok(1) } else { Err(\"bad input: \" + s) } }
+Fn double(s) { let n = parse(s)?; Ok(n * 2) }
+print double(\"1\").unwrap();
+print double(\"x\").is_err();
+print double(\"x\").unwrap_or(0);
+let n = try { double(\"x\")? } catch e { print e; 0 };
+print n;
+";
+    assert_eq!(output(source), "2\ntrue\n0\nbad input: x\n0\n");
+    assert_eq!(run_error("Err(1)?;\n"), RuntimeErrorKind::UncaughtErr(String::from("Err(1)")));
+    assert_eq!(run_error("Err(\"no\").unwrap();\n"), RuntimeErrorKind::UnwrapErr(String::from("Err(\"no\")")));
+}
+
+#[test]
+fn try_catches_runtime_errors_until_its_block_is_left() {
+    assert_eq!(output("print try { 1 + nil } catch e { e };\nprint try { 1 } catch { 2 };\n"), "cannot apply '+' to number and nil\n1\n");
+    assert_eq!(output("Fn fail() { [1][5] }\nprint try { fail(); \"not reached\" } catch { \"caught\" };\n"), "caught\n");
+    // Leaving a `try` any other way also drops its handler, so a later error isn't caught by it.
+    assert_eq!(run_error("while true { try { break; } catch {} }\n1 + nil;\n"), RuntimeErrorKind::TypeMismatch { operator: "+", left: "number", right: "nil" });
+    assert_eq!(run_error("for (let mut i = 0; i < 2; i += 1) { try { continue; } catch {} }\n1 + nil;\n"), RuntimeErrorKind::TypeMismatch { operator: "+", left: "number", right: "nil" });
+    assert_eq!(run_error("Fn f() { try { return 1; } catch {} }\nf();\n1 + nil;\n"), RuntimeErrorKind::TypeMismatch { operator: "+", left: "number", right: "nil" });
+}
+
+#[test]
+fn assertions_report_what_failed() {
+    assert_eq!(output("assert(1 < 2);\nassert_eq(1 + 1, 2);\nassert_eq(\"a\" + \"b\", \"ab\");\nprint \"passed\";\n"), "passed\n");
+    assert_eq!(run_error("assert(1 > 2);\n"), RuntimeErrorKind::AssertFailed(None));
+    assert_eq!(run_error("let total = 0;\nassert(total > 0, \"total was {total}\");\n"), RuntimeErrorKind::AssertFailed(Some(String::from("total was 0"))));
+    let error = run_error("assert_eq([1, \"two\"], nil);\n");
+    assert_eq!(error, RuntimeErrorKind::AssertEqFailed { left: String::from("[1, \"two\"]"), right: String::from("nil") });
+    assert_eq!(error.to_string(), "assertion failed: left is [1, \"two\"], right is nil");
+    assert_eq!(run_error("assert_eq(\"4\", 4);\n").to_string(), "assertion failed: left is \"4\", right is 4");
+    assert_eq!(run_error("panic(\"unreachable\");\n"), RuntimeErrorKind::Panic(Some(String::from("unreachable"))));
+    assert_eq!(run_error("panic();\n"), RuntimeErrorKind::Panic(None));
+    // `try` catches runtime errors, but not these.
+    assert_eq!(run_error("let x = try { assert(false); 1 } catch { 2 };\n"), RuntimeErrorKind::AssertFailed(None));
+    assert_eq!(run_error("let x = try { panic(); 1 } catch { 2 };\n"), RuntimeErrorKind::Panic(None));
+}
+
+#[test]
+fn failed_assertions_carry_a_stack_trace() {
+    let mut vm = VM::new(compile("Fn check(n) {\n  assert_eq(n, 2);\n}\nFn outer() { check(3); }\nouter();\n")).expect("chunk verifies");
+    let InterpretResult::RuntimeError { error, line, stack_trace } = vm.run() else { panic!("expected a runtime error") };
+    assert_eq!(error, RuntimeErrorKind::AssertEqFailed { left: String::from("3"), right: String::from("2") });
+    assert_eq!(line, 2);
+    let frames: Vec<(&str, u32)> = stack_trace.iter().map(|frame| (frame.function.as_str(), frame.line)).collect();
+    assert_eq!(frames, [("check", 2), ("outer", 4), ("<script>", 5)]);
+}